@@ -0,0 +1,355 @@
+//! Derive `IntoLuaMessage` and `FromLuaMessage` for your own types.
+//!
+//! Enable the `derive` feature of `actix-lua` instead of depending on this crate directly.
+//!
+//! ```ignore
+//! use actix_lua::{FromLuaMessage, IntoLuaMessage};
+//!
+//! #[derive(IntoLuaMessage, FromLuaMessage)]
+//! struct User {
+//!     #[lua(rename = "userName")]
+//!     name: String,
+//!     email: Option<String>,
+//!     tags: Vec<String>,
+//! }
+//! ```
+//!
+//! * Structs with named fields are converted to tables. Missing fields are `Nil`.
+//! * A struct with a single unnamed field is converted the same way as the field.
+//!   Other tuple structs are converted to arrays, and unit structs to `Nil`.
+//! * Enums are converted to tables tagged with the variant name, e.g. `{type = "Circle", radius = 1.0}`.
+//!   The value of a tuple variant is stored in `value`, as an array if there's more than one field.
+//!
+//! Attributes:
+//!
+//! * `#[lua(rename = "name")]` on a field or a variant changes its name in Lua.
+//! * `#[lua(tag = "kind")]` on an enum changes the key of the tag, `type` by default.
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::quote;
+use syn::spanned::Spanned;
+use syn::{
+    parse_macro_input, parse_quote, Attribute, Data, DeriveInput, Error, Fields, Generics, Ident,
+    Lit, Meta, NestedMeta, Result,
+};
+
+// key of the enum tag when `#[lua(tag)]` is not set
+const DEFAULT_TAG: &str = "type";
+
+// key of the value of a tuple variant
+const VALUE_KEY: &str = "value";
+
+#[proc_macro_derive(IntoLuaMessage, attributes(lua))]
+pub fn derive_into_lua_message(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    into_lua_message(input)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+#[proc_macro_derive(FromLuaMessage, attributes(lua))]
+pub fn derive_from_lua_message(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    from_lua_message(input)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+/// Options set with `#[lua(...)]`.
+#[derive(Default)]
+struct Options {
+    rename: Option<String>,
+    tag: Option<String>,
+}
+
+fn options(attrs: &[Attribute]) -> Result<Options> {
+    let mut options = Options::default();
+    for attr in attrs.iter().filter(|a| a.path.is_ident("lua")) {
+        let list = match attr.parse_meta()? {
+            Meta::List(list) => list,
+            meta => return Err(Error::new(meta.span(), "expected #[lua(...)]")),
+        };
+        for nested in list.nested.iter() {
+            let (name, value) = match nested {
+                NestedMeta::Meta(Meta::NameValue(nv)) => match nv.lit {
+                    Lit::Str(ref s) => (&nv.path, s.value()),
+                    ref lit => return Err(Error::new(lit.span(), "expected a string")),
+                },
+                _ => return Err(Error::new(nested.span(), "expected `name = \"value\"`")),
+            };
+            if name.is_ident("rename") {
+                options.rename = Some(value);
+            } else if name.is_ident("tag") {
+                options.tag = Some(value);
+            } else {
+                return Err(Error::new(name.span(), "unknown lua attribute"));
+            }
+        }
+    }
+    Ok(options)
+}
+
+// the name of a field or a variant in Lua
+fn lua_name(ident: &Ident, attrs: &[Attribute]) -> Result<String> {
+    Ok(options(attrs)?.rename.unwrap_or_else(|| ident.to_string()))
+}
+
+// add `trait_path` as a bound of every type parameter
+fn with_bounds(generics: &Generics, trait_path: TokenStream2) -> Generics {
+    let mut generics = generics.clone();
+    for param in generics.type_params_mut() {
+        param.bounds.push(parse_quote!(#trait_path));
+    }
+    generics
+}
+
+fn into_lua_message(input: DeriveInput) -> Result<TokenStream2> {
+    let name = &input.ident;
+    let generics = with_bounds(&input.generics, quote!(::actix_lua::IntoLuaMessage));
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    let body =
+        match input.data {
+            Data::Struct(ref data) => match data.fields {
+                Fields::Named(ref fields) => {
+                    let inserts = fields
+                        .named
+                        .iter()
+                        .map(|f| {
+                            let ident = f.ident.as_ref().unwrap();
+                            let key = lua_name(ident, &f.attrs)?;
+                            Ok(quote! {
+                                t.insert(
+                                    #key.to_string(),
+                                    ::actix_lua::IntoLuaMessage::into_lua_message(self.#ident),
+                                );
+                            })
+                        })
+                        .collect::<Result<Vec<_>>>()?;
+                    quote! {
+                        let mut t = ::std::collections::HashMap::new();
+                        #(#inserts)*
+                        ::actix_lua::LuaMessage::Table(t)
+                    }
+                }
+                Fields::Unnamed(ref fields) if fields.unnamed.len() == 1 => {
+                    quote!(::actix_lua::IntoLuaMessage::into_lua_message(self.0))
+                }
+                Fields::Unnamed(ref fields) => {
+                    let items = (0..fields.unnamed.len()).map(|i| {
+                        let i = syn::Index::from(i);
+                        quote!(::actix_lua::IntoLuaMessage::into_lua_message(self.#i))
+                    });
+                    quote!(::actix_lua::LuaMessage::Array(vec![#(#items),*]))
+                }
+                Fields::Unit => quote!(::actix_lua::LuaMessage::Nil),
+            },
+            Data::Enum(ref data) => {
+                let tag = options(&input.attrs)?
+                    .tag
+                    .unwrap_or_else(|| DEFAULT_TAG.to_string());
+                let arms = data
+                .variants
+                .iter()
+                .map(|v| {
+                    let ident = &v.ident;
+                    let variant = lua_name(ident, &v.attrs)?;
+                    let insert_tag = quote! {
+                        t.insert(
+                            #tag.to_string(),
+                            ::actix_lua::LuaMessage::String(#variant.to_string()),
+                        );
+                    };
+                    Ok(match v.fields {
+                        Fields::Named(ref fields) => {
+                            let idents: Vec<_> =
+                                fields.named.iter().map(|f| f.ident.clone().unwrap()).collect();
+                            // bind the fields to names which can't shadow `t`
+                            let vars: Vec<_> = (0..idents.len())
+                                .map(|i| Ident::new(&format!("__f{}", i), Span::call_site()))
+                                .collect();
+                            let keys = fields
+                                .named
+                                .iter()
+                                .map(|f| lua_name(f.ident.as_ref().unwrap(), &f.attrs))
+                                .collect::<Result<Vec<_>>>()?;
+                            quote! {
+                                #name::#ident { #(#idents: #vars),* } => {
+                                    #insert_tag
+                                    #(
+                                        t.insert(
+                                            #keys.to_string(),
+                                            ::actix_lua::IntoLuaMessage::into_lua_message(#vars),
+                                        );
+                                    )*
+                                }
+                            }
+                        }
+                        Fields::Unnamed(ref fields) => {
+                            let vars: Vec<_> = (0..fields.unnamed.len())
+                                .map(|i| Ident::new(&format!("v{}", i), Span::call_site()))
+                                .collect();
+                            let value = if vars.len() == 1 {
+                                quote!(::actix_lua::IntoLuaMessage::into_lua_message(v0))
+                            } else {
+                                quote! {
+                                    ::actix_lua::LuaMessage::Array(vec![
+                                        #(::actix_lua::IntoLuaMessage::into_lua_message(#vars)),*
+                                    ])
+                                }
+                            };
+                            quote! {
+                                #name::#ident(#(#vars),*) => {
+                                    #insert_tag
+                                    t.insert(#VALUE_KEY.to_string(), #value);
+                                }
+                            }
+                        }
+                        Fields::Unit => quote!(#name::#ident => { #insert_tag }),
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+                quote! {
+                    let mut t = ::std::collections::HashMap::new();
+                    match self {
+                        #(#arms)*
+                    }
+                    ::actix_lua::LuaMessage::Table(t)
+                }
+            }
+            Data::Union(_) => {
+                return Err(Error::new(
+                    input.span(),
+                    "IntoLuaMessage can't be derived for unions",
+                ))
+            }
+        };
+
+    Ok(quote! {
+        impl #impl_generics ::actix_lua::IntoLuaMessage for #name #ty_generics #where_clause {
+            fn into_lua_message(self) -> ::actix_lua::LuaMessage {
+                #body
+            }
+        }
+
+        impl #impl_generics ::std::convert::From<#name #ty_generics> for ::actix_lua::LuaMessage
+            #where_clause
+        {
+            fn from(v: #name #ty_generics) -> ::actix_lua::LuaMessage {
+                ::actix_lua::IntoLuaMessage::into_lua_message(v)
+            }
+        }
+    })
+}
+
+fn from_lua_message(input: DeriveInput) -> Result<TokenStream2> {
+    let name = &input.ident;
+    let generics = with_bounds(&input.generics, quote!(::actix_lua::FromLuaMessage));
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    let body = match input.data {
+        Data::Struct(ref data) => {
+            let construct = construct(quote!(#name), &data.fields, quote!(msg))?;
+            quote!(#construct)
+        }
+        Data::Enum(ref data) => {
+            let tag = options(&input.attrs)?
+                .tag
+                .unwrap_or_else(|| DEFAULT_TAG.to_string());
+            let arms = data
+                .variants
+                .iter()
+                .map(|v| {
+                    let ident = &v.ident;
+                    let variant = lua_name(ident, &v.attrs)?;
+                    let construct = match v.fields {
+                        Fields::Named(_) => {
+                            let fields = named_fields(&v.fields)?;
+                            quote!(Ok(#name::#ident { #(#fields),* }))
+                        }
+                        Fields::Unnamed(_) => {
+                            // the fields are stored in `value`
+                            let value = quote!(t.remove(#VALUE_KEY).unwrap_or(::actix_lua::LuaMessage::Nil));
+                            let construct = construct(quote!(#name::#ident), &v.fields, value)?;
+                            quote! {
+                                (|| -> ::std::result::Result<Self, ::actix_lua::LuaActorError> {
+                                    #construct
+                                })()
+                                .map_err(|e| ::actix_lua::__private::in_field(e, #VALUE_KEY))
+                            }
+                        }
+                        Fields::Unit => quote!(Ok(#name::#ident)),
+                    };
+                    Ok(quote!(#variant => #construct,))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let type_name = name.to_string();
+            quote! {
+                let mut t = ::actix_lua::__private::table(msg)?;
+                let tag: String = ::actix_lua::__private::field(&mut t, #tag)?;
+                match tag.as_str() {
+                    #(#arms)*
+                    tag => Err(::actix_lua::__private::unknown_variant(tag, #type_name)),
+                }
+            }
+        }
+        Data::Union(_) => {
+            return Err(Error::new(
+                input.span(),
+                "FromLuaMessage can't be derived for unions",
+            ))
+        }
+    };
+
+    Ok(quote! {
+        impl #impl_generics ::actix_lua::FromLuaMessage for #name #ty_generics #where_clause {
+            fn from_lua_message(
+                msg: ::actix_lua::LuaMessage,
+            ) -> ::std::result::Result<Self, ::actix_lua::LuaActorError> {
+                #body
+            }
+        }
+    })
+}
+
+// `field: __private::field(&mut t, "key")?` for each named field
+fn named_fields(fields: &Fields) -> Result<Vec<TokenStream2>> {
+    fields
+        .iter()
+        .map(|f| {
+            let ident = f.ident.as_ref().unwrap();
+            let key = lua_name(ident, &f.attrs)?;
+            Ok(quote!(#ident: ::actix_lua::__private::field(&mut t, #key)?))
+        })
+        .collect()
+}
+
+// an expression converting the message `msg` to `path` with `fields`
+fn construct(path: TokenStream2, fields: &Fields, msg: TokenStream2) -> Result<TokenStream2> {
+    Ok(match fields {
+        Fields::Named(_) => {
+            let fields = named_fields(fields)?;
+            quote! {{
+                let mut t = ::actix_lua::__private::table(#msg)?;
+                Ok(#path { #(#fields),* })
+            }}
+        }
+        Fields::Unnamed(ref fields) if fields.unnamed.len() == 1 => {
+            quote!(Ok(#path(::actix_lua::FromLuaMessage::from_lua_message(#msg)?)))
+        }
+        Fields::Unnamed(ref fields) => {
+            let len = fields.unnamed.len();
+            let items = (0..len).map(|i| quote!(::actix_lua::__private::element(&mut items, #i)?));
+            quote! {{
+                let mut items = ::actix_lua::__private::array(#msg, #len)?;
+                Ok(#path(#(#items),*))
+            }}
+        }
+        Fields::Unit => quote!({
+            let _ = #msg;
+            Ok(#path)
+        }),
+    })
+}
@@ -1,3 +1 @@
-return function (msg)
-  print("received " .. msg)
-end
\ No newline at end of file
+print("received " .. ctx.msg)
@@ -10,32 +10,13 @@ use std::time::Duration;
 
 lazy_static! {
     static ref SCRIPT_ACTOR: Addr<LuaActor> = {
+        // `script.lua` is reloaded when it's changed.
+        // If the new version fails to compile, the error is printed and the previous version keeps running.
         LuaActorBuilder::new()
-            .on_handle_with_lua(
-                r#"
-            -- flush the require cache
-            --
-            -- in production you shouldn't flush it for every message,
-            -- instead you should wait for some kind of "singal", such as file watcher
-            -- to flush it only when necessary
-            package.loaded["script"] = nil
-
-            -- try to load the script, print error if failed
-            local ok, ret = pcall(require, "script")
-            if not ok then
-                print(ret)
-            end
-
-            local handler = ret
-            -- try to call the script
-            local ok, ret = pcall(handler, ctx.msg)
-            if not ok then
-                print(ret)
-            end
-
-            return 0
-            "#,
-            ).build()
+            .on_handle("script.lua")
+            .watch(true)
+            .watch_interval(Duration::from_millis(500))
+            .build()
             .unwrap()
             .start()
     };
@@ -5,12 +5,11 @@ extern crate env_logger;
 extern crate futures;
 
 use actix::prelude::*;
-use actix_lua::{LuaActor, LuaActorBuilder, LuaMessage};
+use actix_lua::{IntoLuaMessage, LuaActor, LuaActorBuilder, LuaMessage};
 use actix_web::{
     http, middleware, server, App, AsyncResponder, FutureResponse, HttpResponse, Path, State,
 };
 use futures::Future;
-use std::collections::HashMap;
 use std::fs::File;
 use std::io::prelude::*;
 
@@ -18,12 +17,17 @@ struct AppState {
     lua: Addr<LuaActor>,
 }
 
-fn build_message(path: Path<String>, method: &str) -> LuaMessage {
-    let mut t = HashMap::new();
-    t.insert("path".to_string(), LuaMessage::from(path.into_inner()));
-    t.insert("method".to_string(), LuaMessage::from(method.to_string()));
+#[derive(IntoLuaMessage)]
+struct Request {
+    path: String,
+    method: String,
+}
 
-    LuaMessage::from(t)
+fn build_message(path: Path<String>, method: &str) -> LuaMessage {
+    LuaMessage::from(Request {
+        path: path.into_inner(),
+        method: method.to_string(),
+    })
 }
 
 fn run_lua(state: State<AppState>, t: LuaMessage) -> FutureResponse<HttpResponse> {
@@ -1,13 +1,29 @@
 use ::actix::prelude::*;
 use ::actix::ActorContext;
+use futures::future::{self, Either};
+use futures::sync::oneshot;
+use futures::Future;
+use log::error;
 use rlua::Error as LuaError;
-use rlua::{FromLua, Function, Lua, MultiValue, ToLua, Value};
-
-use crate::message::LuaMessage;
-use std::cell::RefCell;
+use rlua::{
+    Context as LuaContext, FromLua, Function, Lua, MultiValue, Table, Thread, ToLua, Value,
+};
+
+use crate::builder::LuaActorBuilder;
+use crate::error::LuaActorError;
+use crate::limits::Limits;
+use crate::message::{LuaCall, LuaMessage, LuaReply, Utf8Policy};
+use crate::persist::Persistence;
+use crate::registry::{Get, LuaRegistry, Register, UnregisterAll};
+use crate::watcher::ScriptWatcher;
+use std::cell::{Cell, RefCell};
+use std::cmp;
 use std::collections::HashMap;
 use std::str;
-use std::time::Duration;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::timer::Timeout;
 
 /// Top level struct which holds a lua state for itself.
 ///
@@ -25,26 +41,158 @@ use std::time::Duration;
 /// ### `ctx.notify_later(msg, seconds)`
 /// Send message `msg` to self after specified period of time.
 ///
-/// ### `local result = ctx.send(recipient, msg)`
+/// ### `local timer = ctx.run_later(msg, seconds)`
+/// Send message `msg` to self after `seconds`, which can be fractional.
+/// Returns a handle which can be passed to `ctx.cancel`.
+///
+/// ### `local timer = ctx.run_interval(msg, seconds)`
+/// Send message `msg` to self every `seconds` until the timer is cancelled.
+///
+/// ### `ctx.cancel(timer)`
+/// Cancel a timer started with `ctx.run_later` or `ctx.run_interval`.
+/// Returns `false` if the timer already fired or was cancelled.
+///
+/// ### `local result, err = ctx.send(recipient, msg, options)`
 /// Send message `msg` to `recipient asynchronously and wait for response.
 ///
-/// Calling `ctx.send` yield the current coroutine and returns a `ThreadYield(thread_id)` message.
+/// `recipient` is either the name of a recipient added with `add_recipients`,
+/// or an address received as a `LuaMessage::Recipient`, which can be kept in `ctx.state`.
+/// Names which are not in the recipient list are looked up in [`LuaRegistry`].
+///
+/// Calling `ctx.send` yield the current coroutine.
 /// LuaActor will wait for the response and resume the yielded coroutine once the response is returned.
+/// The reply to the message being handled is deferred until the coroutine returns.
+/// The actor keeps handling other messages in the meantime, so `ctx.state` may have been
+/// changed by them when `ctx.send` returns.
+///
+/// If the recipient is unknown, its mailbox is closed or the response doesn't come within
+/// `options.timeout` seconds, `ctx.send` returns `nil` and the error message.
+/// Without a timeout, `ctx.send` waits for the response forever.
 ///
 /// Equivalent to `actix::Recipient.send`.
 ///
+/// ### `local replies, errors = ctx.gather({ { recipient, msg }, ... })`
+/// Send all the messages at once and wait for all the responses.
+///
+/// `replies[i]` is the response to the `i`th message. If it can't be delivered, `replies[i]` is `nil`
+/// and `errors[i]` is the error message.
+///
+/// ### `local reply, i = ctx.race({ { recipient, msg }, ... })`
+/// Send all the messages at once and wait for the first response. `i` is the index of the message
+/// it responds to. An error is raised if none of the messages can be delivered.
+///
+/// Like `ctx.send`, both suspend the current coroutine while other messages are handled.
+///
 /// ### `ctx.do_send(recipient, msg)`
-/// Send message `msg` to `recipient`.
+/// Send message `msg` to `recipient`, a recipient name or address like `ctx.send`.
+/// An error is raised if the mailbox of a recipient of the actor is closed.
+///
+/// Names which are not in the recipients of the actor are looked up in [`LuaRegistry`]
+/// without waiting for the lookup. If the name isn't registered, the error is logged.
 ///
 /// Equivalent to `actix::Recipient.do_send`.
 ///
+/// ### `ctx.sleep(seconds)`
+/// Suspend the current coroutine for `seconds`, which can be fractional.
+/// The actor keeps handling other messages in the meantime.
+/// Like `ctx.send`, the reply to the message being handled is deferred until the coroutine returns.
+///
+/// ### `ctx.self`
+/// The address of the actor. It can be sent to other actors in a message so they can reply to it.
+///
+/// ### `ctx.recipients()`
+/// Returns the names of the recipients, sorted.
+///
+/// ### `ctx.has_recipient(name)`
+/// Returns `true` if a recipient named `name` exists.
+///
+/// ### `ctx.register(name, recipient)`
+/// Add `recipient`, an address or the name of another recipient, to the recipient list as `name`.
+/// An existing recipient with the same name is replaced.
+///
+/// ### `ctx.register_global(name)`
+/// Register the actor as `name` in [`LuaRegistry`]. The actor is unregistered when it stops.
+///
+/// ### `local child = ctx.spawn({ started = "...", handle = "...", stopped = "..." })`
+/// Create a child `LuaActor` with the given scripts and start it on the current arbiter.
+/// Returns the address of the child, which can be used with `ctx.send` and `ctx.do_send`.
+///
+/// Children are built with the sandbox profile, limits and UTF-8 policy of the parent.
+/// The number of running descendants can be limited with `LuaActorBuilder::max_children`.
+/// They are stopped when the parent stops. When a child stops, the parent handles the message
+/// `{ type = "child_stopped", child = <address of the child> }`.
+///
+/// ### `local child = ctx.spawn_file(filename)`
+/// Like `ctx.spawn`, with the lua file `filename` as the `handle` script.
+/// Only files in the directory set with `LuaActorBuilder::spawn_dir` can be spawned,
+/// and only by actors with the `SandboxProfile::full()` profile.
+///
 /// ### `ctx.terminate()`
 /// Terminate actor execution.
 ///
+/// ### Errors
+/// Errors raised by scripts are converted to [`LuaActorError`].
+/// A failed handler doesn't stop the actor, it keeps handling the following messages.
+///
+/// A `LuaMessage` whose handler fails is replied with `LuaMessage::Nil` and the error is logged.
+/// Send a [`LuaCall`] instead to receive the error.
+///
 /// [`LuaActorBuilder`]: struct.LuaActorBuilder.html
+/// [`LuaActorError`]: enum.LuaActorError.html
+/// [`LuaCall`]: struct.LuaCall.html
+/// [`LuaRegistry`]: struct.LuaRegistry.html
 pub struct LuaActor {
     vm: Lua,
     pub recipients: HashMap<String, Recipient<LuaMessage>>,
+    // reply channels of suspended coroutines, keyed by thread id
+    replies: HashMap<i64, oneshot::Sender<Result<LuaMessage, LuaActorError>>>,
+    limits: Limits,
+    watcher: Option<ScriptWatcher>,
+    persistence: Option<Persistence>,
+    // builder of the actors created with `ctx.spawn`
+    spawner: LuaActorBuilder,
+    children: Vec<Addr<LuaActor>>,
+    parent: Option<Addr<LuaActor>>,
+    // the count of running descendants of the root actor, if the actor was created with `ctx.spawn`
+    spawned: Option<Arc<AtomicUsize>>,
+    // names to register in `LuaRegistry` when the actor starts
+    names: Vec<String>,
+    registered: bool,
+    timers: Timers,
+    // suspended coroutines, keyed by thread id
+    suspended: HashMap<i64, Suspension>,
+    // the error a coroutine is resumed with, raised as is by the functions which don't return it
+    resume_error: Option<LuaActorError>,
+    max_suspended: Option<usize>,
+    max_suspended_age: Option<Duration>,
+}
+
+struct Suspension {
+    since: Instant,
+    waiting_on: Vec<String>,
+}
+
+impl Suspension {
+    fn new(waiting_on: Vec<String>) -> Suspension {
+        Suspension {
+            since: Instant::now(),
+            waiting_on,
+        }
+    }
+}
+
+// timers started with `ctx.run_later` and `ctx.run_interval`, keyed by the handle returned to lua
+#[derive(Default)]
+struct Timers {
+    handles: HashMap<i64, SpawnHandle>,
+    seq: i64,
+}
+
+impl Timers {
+    fn next_id(&mut self) -> i64 {
+        self.seq += 1;
+        self.seq
+    }
 }
 
 impl LuaActor {
@@ -53,40 +201,49 @@ impl LuaActor {
         started: Option<String>,
         handle: Option<String>,
         stopped: Option<String>,
-    ) -> Result<LuaActor, LuaError> {
+    ) -> Result<LuaActor, LuaActorError> {
         let prelude = include_str!("lua/prelude.lua");
         vm.context(|ctx| {
-            ctx.load(prelude).set_name("Prelude")?.exec()?;
-            {
-                let load: Function = ctx.globals().get("__load")?;
-                if let Some(script) = started {
-                    let res = load.call::<(String, String), ()>((script, "started".to_string()));
-
-                    if let Err(e) = res {
-                        return Result::Err(e);
-                    }
-                }
-                if let Some(script) = handle {
-                    let res = load.call::<(String, String), ()>((script, "handle".to_string()));
-
-                    if let Err(e) = res {
-                        return Result::Err(e);
-                    }
-                }
-                if let Some(script) = stopped {
-                    let res = load.call::<(String, String), ()>((script, "stopped".to_string()));
-
-                    if let Err(e) = res {
-                        return Result::Err(e);
-                    }
-                }
+            // resume coroutines from rust to keep the type and the traceback of their errors
+            let resume = ctx.create_function(|_, (thread, args): (Thread, MultiValue)| {
+                thread.resume::<_, MultiValue>(args)
+            })?;
+            ctx.load(prelude)
+                .set_name("Prelude")?
+                .call::<_, ()>(resume)
+                .map_err(LuaActorError::compile)?;
+            #[cfg(feature = "json")]
+            crate::json::preload(ctx)?;
+            if let Some(script) = started {
+                load_script(ctx, "started", &script)?;
             }
-            Ok(())
+            if let Some(script) = handle {
+                load_script(ctx, "handle", &script)?;
+            }
+            if let Some(script) = stopped {
+                load_script(ctx, "stopped", &script)?;
+            }
+            Ok::<(), LuaActorError>(())
         })?;
 
         Result::Ok(LuaActor {
             vm,
             recipients: HashMap::new(),
+            replies: HashMap::new(),
+            limits: Limits::default(),
+            watcher: None,
+            persistence: None,
+            spawner: LuaActorBuilder::new(),
+            children: vec![],
+            parent: None,
+            spawned: None,
+            names: vec![],
+            registered: false,
+            timers: Timers::default(),
+            suspended: HashMap::new(),
+            resume_error: None,
+            max_suspended: None,
+            max_suspended_age: None,
         })
     }
 
@@ -94,13 +251,15 @@ impl LuaActor {
         started: Option<String>,
         handle: Option<String>,
         stopped: Option<String>,
-    ) -> Result<LuaActor, LuaError> {
+    ) -> Result<LuaActor, LuaActorError> {
         let vm = Lua::new();
         Self::new_with_vm(vm, started, handle, stopped)
     }
 
     /// Add a recipient to the actor's recipient list.
     /// You can send message to the recipient via `name` with the context API `ctx.send(name, message)`
+    ///
+    /// Use the `AddRecipient` message to add a recipient to a running actor.
     pub fn add_recipients(
         &mut self,
         name: &str,
@@ -108,28 +267,261 @@ impl LuaActor {
     ) -> Option<Recipient<LuaMessage>> {
         self.recipients.insert(name.to_string(), rec)
     }
+
+    /// Returns the memory currently used by the lua VM, in bytes.
+    pub fn used_memory(&self) -> usize {
+        self.vm.used_memory()
+    }
+
+    /// Returns the coroutines suspended in `ctx.send`, `ctx.gather`, `ctx.race` or `ctx.sleep`.
+    pub fn stats(&self) -> LuaActorStats {
+        let now = Instant::now();
+        let mut suspended = self
+            .suspended
+            .iter()
+            .map(|(tid, s)| SuspendedThread {
+                thread_id: *tid,
+                age: now.duration_since(s.since),
+                waiting_on: s.waiting_on.clone(),
+            })
+            .collect::<Vec<_>>();
+        suspended.sort_by(|a, b| b.age.cmp(&a.age).then(a.thread_id.cmp(&b.thread_id)));
+        LuaActorStats { suspended }
+    }
+
+    /// Returns a copy of `ctx.state`.
+    pub fn snapshot_state(&self) -> Result<LuaMessage, LuaActorError> {
+        self.vm.context(|ctx| {
+            let lua_ctx: Table = ctx.globals().get("ctx")?;
+            let state: Value = lua_ctx.get("state")?;
+            Ok(LuaMessage::from_lua(state, ctx)?)
+        })
+    }
+
+    /// Replace `ctx.state` with `state`, which must be a `LuaMessage::Table` or `LuaMessage::Array`.
+    pub fn restore_state(&mut self, state: LuaMessage) -> Result<(), LuaActorError> {
+        if !matches!(state, LuaMessage::Table(_) | LuaMessage::Array(_)) {
+            return Err(LuaActorError::Conversion(
+                "state must be a table".to_string(),
+            ));
+        }
+        self.vm.context(|ctx| {
+            let lua_ctx: Table = ctx.globals().get("ctx")?;
+            lua_ctx.set("state", state)?;
+            Ok(())
+        })
+    }
+
+    pub(crate) fn set_utf8_policy(&mut self, policy: Utf8Policy) -> Result<(), LuaActorError> {
+        self.vm.context(|ctx| policy.install(ctx))?;
+        Ok(())
+    }
+
+    pub(crate) fn set_persistence(&mut self, persistence: Persistence) {
+        self.persistence = Some(persistence);
+    }
+
+    fn persist(&self) {
+        if let Some(ref persistence) = self.persistence {
+            let res = self
+                .snapshot_state()
+                .and_then(|state| Ok(persistence.save(&state)?));
+            if let Err(e) = res {
+                error!("lua actor persist state failed: {}", e);
+            }
+        }
+    }
+
+    pub(crate) fn set_limits(&mut self, limits: Limits) -> Result<(), LuaActorError> {
+        limits.install(&self.vm)?;
+        self.limits = limits;
+        Ok(())
+    }
+
+    pub(crate) fn set_spawner(&mut self, spawner: LuaActorBuilder) {
+        self.spawner = spawner;
+    }
+
+    pub(crate) fn set_spawned(&mut self, descendants: Arc<AtomicUsize>) {
+        self.spawned = Some(descendants);
+    }
+
+    pub(crate) fn set_names(&mut self, names: Vec<String>) {
+        self.names = names;
+    }
+
+    pub(crate) fn set_suspend_limits(&mut self, count: Option<usize>, age: Option<Duration>) {
+        self.max_suspended = count;
+        self.max_suspended_age = age;
+    }
+
+    // fail the oldest suspended coroutines over `max_suspended`, and the ones older than `max_suspended_age`
+    fn evict_suspended(&mut self) {
+        let mut threads = self
+            .suspended
+            .iter()
+            .map(|(tid, s)| (s.since, *tid))
+            .collect::<Vec<_>>();
+        threads.sort();
+        let over = self
+            .max_suspended
+            .map_or(0, |max| threads.len().saturating_sub(max));
+        let now = Instant::now();
+        let max_age = self.max_suspended_age;
+        let evicted = threads
+            .into_iter()
+            .enumerate()
+            .filter(|(i, (since, _))| {
+                *i < over || max_age.is_some_and(|age| now.duration_since(*since) >= age)
+            })
+            .map(|(_, (_, tid))| tid)
+            .collect::<Vec<_>>();
+        for tid in evicted {
+            self.evict(tid);
+        }
+    }
+
+    fn evict(&mut self, tid: i64) {
+        self.suspended.remove(&tid);
+        let res = self.vm.context(|ctx| {
+            let threads: Table = ctx.globals().get("__threads")?;
+            threads.set(tid, Value::Nil)
+        });
+        if let Err(e) = res {
+            error!("lua actor evict failed: {}", e);
+        }
+        if let Some(tx) = self.replies.remove(&tid) {
+            let _ = tx.send(Err(LuaActorError::Evicted));
+        }
+    }
+
+    pub(crate) fn set_watcher(&mut self, watcher: ScriptWatcher) {
+        self.watcher = Some(watcher);
+    }
+
+    // compile all given scripts before replacing any of them
+    fn reload(
+        &mut self,
+        scripts: ReloadScripts,
+        ctx: &mut Context<Self>,
+    ) -> Result<(), LuaActorError> {
+        // the new handlers are kept in the registry until `migrate` succeeded
+        let compiled = self.vm.context(|lua_ctx| {
+            let mut compiled = vec![];
+            for (name, script) in &[
+                ("started", &scripts.started),
+                ("handle", &scripts.handle),
+                ("stopped", &scripts.stopped),
+            ] {
+                if let Some(script) = script {
+                    let f = compile_script(lua_ctx, name, script)?;
+                    compiled.push((*name, lua_ctx.create_registry_value(f)?));
+                }
+            }
+            if let Some(ref script) = scripts.migrate {
+                load_script(lua_ctx, "migrate", script)?;
+            }
+            Ok::<_, LuaActorError>(compiled)
+        })?;
+
+        let migrated = if scripts.migrate.is_some() {
+            invoke(self, ctx, "__migrate", vec![]).map(|_| ())
+        } else {
+            Ok(())
+        };
+
+        self.vm.context(|lua_ctx| {
+            let load: Function = lua_ctx.globals().get("__load")?;
+            for (name, key) in compiled {
+                let f: Function = lua_ctx.registry_value(&key)?;
+                lua_ctx.remove_registry_value(key)?;
+                if migrated.is_ok() {
+                    load.call::<_, ()>((f, name))?;
+                }
+            }
+            Ok::<(), LuaActorError>(())
+        })?;
+        migrated
+    }
+
+    fn poll_scripts(&mut self, ctx: &mut Context<Self>) {
+        let res = match self.watcher {
+            Some(ref mut watcher) => watcher.poll(),
+            None => return,
+        };
+        match res {
+            Ok(Some(scripts)) => {
+                if let Err(e) = self.reload(scripts, ctx) {
+                    error!("lua actor reload failed: {}", e);
+                }
+            }
+            Ok(None) => (),
+            Err(e) => error!("lua actor reload failed: {}", e),
+        }
+    }
+}
+
+// Compile the script and register it as `name`.
+// Scripts are compiled in Rust since `load` might be removed by the sandbox.
+fn load_script(ctx: LuaContext, name: &str, script: &str) -> Result<(), LuaActorError> {
+    let f = compile_script(ctx, name, script)?;
+    let load: Function = ctx.globals().get("__load")?;
+    load.call::<_, ()>((f, name))?;
+    Ok(())
+}
+
+fn compile_script<'lua>(
+    ctx: LuaContext<'lua>,
+    name: &str,
+    script: &str,
+) -> Result<Function<'lua>, LuaActorError> {
+    ctx.load(script)
+        .set_name(name)?
+        .into_function()
+        .map_err(LuaActorError::compile)
 }
 
 // Remove all `self` usage with a independent function `invoke`.
 fn invoke(
-    self_addr: &Recipient<SendAttempt>,
+    act: &mut LuaActor,
     ctx: &mut Context<LuaActor>,
-    vm: &mut Lua,
-    recs: &mut HashMap<String, Recipient<LuaMessage>>,
     func_name: &str,
     args: Vec<LuaMessage>,
-) -> Result<LuaMessage, LuaError> {
+) -> Result<LuaMessage, LuaActorError> {
+    let LuaActor {
+        ref vm,
+        ref limits,
+        ref mut recipients,
+        ref spawner,
+        ref mut children,
+        ref mut registered,
+        ref mut timers,
+        ref mut suspended,
+        ref mut resume_error,
+        ..
+    } = *act;
+    limits.reset();
+
+    let addr = ctx.address();
+    let self_addr: Recipient<SendAttempt> = addr.clone().recipient();
+    let gather_addr: Recipient<GatherAttempt> = addr.clone().recipient();
+    let self_recipient = LuaMessage::Recipient(addr.clone().recipient());
+
     // `ctx` is used in multiple closure in the lua scope.
     // to create multiple borrow in closures, we use RefCell to move the borrow-checking to runtime.
     // Voliating the check will result in panic. Which shouldn't happend(I think) since lua is single-threaded.
     let ctx = RefCell::new(ctx);
-    let recs = RefCell::new(recs);
+    let recs = RefCell::new(recipients);
+    let children = RefCell::new(children);
+    let timers = RefCell::new(timers);
+    let suspended = RefCell::new(suspended);
+    let register_global_called = Cell::new(false);
 
-    vm.context(|lua_ctx| {
+    let res = vm.context(|lua_ctx| {
         let iter = args
             .into_iter()
-            .map(|msg| msg.to_lua(lua_ctx).unwrap())
-            .collect();
+            .map(|msg| msg.to_lua(lua_ctx))
+            .collect::<Result<_, LuaError>>()?;
         let args = MultiValue::from_vec(iter);
         // We can't create a function with references to `self` and is 'static since `self` already owns Lua.
         // A function within Lua owning `self` creates self-borrowing cycle.
@@ -152,28 +544,102 @@ fn invoke(
             })?;
             globals.set("notify", notify)?;
 
-            let notify_later = scope.create_function_mut(|_, (msg, secs): (LuaMessage, u64)| {
+            let notify_later = scope.create_function_mut(|_, (msg, secs): (LuaMessage, f64)| {
                 let mut ctx = ctx.borrow_mut();
-                ctx.notify_later(msg, Duration::new(secs, 0));
+                ctx.notify_later(msg, duration(secs)?);
                 Ok(())
             })?;
             globals.set("notify_later", notify_later)?;
 
-            let do_send =
-                scope.create_function_mut(|_, (recipient_name, msg): (String, LuaMessage)| {
-                    let recs = recs.borrow_mut();
-                    let rec = recs.get(&recipient_name);
+            let run_later = scope.create_function_mut(|_, (msg, secs): (LuaMessage, f64)| {
+                let dur = duration(secs)?;
+                let mut timers = timers.borrow_mut();
+                let id = timers.next_id();
+                let handle = ctx.borrow_mut().run_later(dur, move |act, ctx| {
+                    act.timers.handles.remove(&id);
+                    ctx.notify(msg);
+                });
+                timers.handles.insert(id, handle);
+                Ok(id)
+            })?;
+            globals.set("run_later", run_later)?;
+
+            let run_interval = scope.create_function_mut(|_, (msg, secs): (LuaMessage, f64)| {
+                let dur = duration(secs)?;
+                let mut timers = timers.borrow_mut();
+                let id = timers.next_id();
+                let handle = ctx
+                    .borrow_mut()
+                    .run_interval(dur, move |_, ctx| ctx.notify(msg.clone()));
+                timers.handles.insert(id, handle);
+                Ok(id)
+            })?;
+            globals.set("run_interval", run_interval)?;
+
+            let cancel = scope.create_function_mut(|_, id: i64| {
+                match timers.borrow_mut().handles.remove(&id) {
+                    Some(handle) => Ok(ctx.borrow_mut().cancel_future(handle)),
+                    None => Ok(false),
+                }
+            })?;
+            globals.set("cancel", cancel)?;
+
+            let sleep = scope.create_function_mut(|_, (secs, cb_thread_id): (f64, i64)| {
+                let dur = duration(secs)?;
+                ctx.borrow_mut().run_later(dur, move |_, ctx| {
+                    ctx.notify(SendAttemptResult {
+                        msg: Ok(LuaMessage::Nil),
+                        cb_thread_id,
+                    })
+                });
+                suspended
+                    .borrow_mut()
+                    .insert(cb_thread_id, Suspension::new(vec![]));
+                Ok(())
+            })?;
+            globals.set("sleep", sleep)?;
 
-                    // TODO: error handling?
-                    if let Some(r) = rec {
-                        r.do_send(msg).unwrap();
+            // returns true if the coroutine has to wait for `LuaRegistry` to find the recipient
+            let do_send =
+                scope.create_function_mut(|_, (recipient, msg): (LuaMessage, LuaMessage)| {
+                    match resolve(&recs.borrow(), recipient) {
+                        Ok(r) => r
+                            .do_send(msg)
+                            .map_err(|_| LuaActorError::Mailbox(MailboxError::Closed).into()),
+                        Err(LuaActorError::MissingRecipient(name)) => {
+                            forward(name, msg);
+                            Ok(())
+                        }
+                        Err(e) => Err(e.into()),
                     }
-                    Ok(())
                 })?;
             globals.set("do_send", do_send)?;
 
+            let raise_resume_error =
+                scope.create_function_mut(|_, ()| match resume_error.take() {
+                    Some(e) => Err(e.into()),
+                    None => Ok(()),
+                })?;
+            globals.set("raise_resume_error", raise_resume_error)?;
+
             let send = scope.create_function_mut(
-                |_, (recipient_name, msg, cb_thread_id): (String, LuaMessage, i64)| {
+                |_,
+                 (recipient, msg, cb_thread_id, options): (
+                    LuaMessage,
+                    LuaMessage,
+                    i64,
+                    Option<Table>,
+                )| {
+                    let waiting_on = vec![describe(&recipient)];
+                    let recipient = target(&recs.borrow(), recipient)?;
+                    let timeout = match options {
+                        Some(options) => match options.get::<_, Option<f64>>("timeout")? {
+                            Some(secs) => Some(duration(secs)?),
+                            None => None,
+                        },
+                        None => None,
+                    };
+
                     // we can't create a lua function which owns `self`
                     // but `self` is needed for resolving `send` future.
                     //
@@ -181,17 +647,51 @@ fn invoke(
                     // and resolving `send` future in the `handle` function.
                     self_addr
                         .do_send(SendAttempt {
-                            recipient_name,
+                            recipient,
                             msg,
+                            timeout,
                             cb_thread_id,
                         })
-                        .unwrap();
-
+                        .map_err(|_| LuaActorError::Mailbox(MailboxError::Closed))?;
+                    suspended
+                        .borrow_mut()
+                        .insert(cb_thread_id, Suspension::new(waiting_on));
                     Ok(())
                 },
             )?;
             globals.set("send", send)?;
 
+            let gather = scope.create_function_mut(
+                |_, (requests, race, cb_thread_id): (Vec<Table>, bool, i64)| {
+                    if race && requests.is_empty() {
+                        return Err(
+                            LuaActorError::Conversion("ctx.race: no requests".to_string()).into(),
+                        );
+                    }
+                    let mut waiting_on = vec![];
+                    let requests = requests
+                        .into_iter()
+                        .map(|req| {
+                            let recipient = req.get(1)?;
+                            waiting_on.push(describe(&recipient));
+                            Ok((target(&recs.borrow(), recipient)?, req.get(2)?))
+                        })
+                        .collect::<Result<Vec<_>, LuaError>>()?;
+                    gather_addr
+                        .do_send(GatherAttempt {
+                            requests,
+                            race,
+                            cb_thread_id,
+                        })
+                        .map_err(|_| LuaActorError::Mailbox(MailboxError::Closed))?;
+                    suspended
+                        .borrow_mut()
+                        .insert(cb_thread_id, Suspension::new(waiting_on));
+                    Ok(())
+                },
+            )?;
+            globals.set("gather", gather)?;
+
             let terminate = scope.create_function_mut(|_, _: LuaMessage| {
                 let mut ctx = ctx.borrow_mut();
                 ctx.terminate();
@@ -199,197 +699,2016 @@ fn invoke(
             })?;
             globals.set("terminate", terminate)?;
 
+            let spawn = scope.create_function_mut(|_, spec: Table| {
+                let mut builder = spawner.clone();
+                if let Some(script) = spec.get::<_, Option<String>>("started")? {
+                    builder = builder.on_started_with_lua(&script);
+                }
+                if let Some(script) = spec.get::<_, Option<String>>("handle")? {
+                    builder = builder.on_handle_with_lua(&script);
+                }
+                if let Some(script) = spec.get::<_, Option<String>>("stopped")? {
+                    builder = builder.on_stopped_with_lua(&script);
+                }
+                Ok(spawn_child(builder, &addr, &mut children.borrow_mut())?)
+            })?;
+            globals.set("spawn", spawn)?;
+
+            let spawn_file = scope.create_function_mut(|_, filename: String| {
+                let builder = spawner.clone().with_file(&filename)?;
+                Ok(spawn_child(builder, &addr, &mut children.borrow_mut())?)
+            })?;
+            globals.set("spawn_file", spawn_file)?;
+
+            let recipients = scope.create_function_mut(|_, ()| {
+                let mut names = recs.borrow().keys().cloned().collect::<Vec<_>>();
+                names.sort();
+                Ok(names)
+            })?;
+            globals.set("recipients", recipients)?;
+
+            let has_recipient = scope
+                .create_function_mut(|_, name: String| Ok(recs.borrow().contains_key(&name)))?;
+            globals.set("has_recipient", has_recipient)?;
+
+            let register =
+                scope.create_function_mut(|_, (name, recipient): (String, LuaMessage)| {
+                    let recipient = resolve(&recs.borrow(), recipient)?;
+                    recs.borrow_mut().insert(name, recipient);
+                    Ok(())
+                })?;
+            globals.set("register", register)?;
+
+            let register_global = scope.create_function_mut(|_, name: String| {
+                LuaRegistry::from_registry().do_send(Register {
+                    name,
+                    recipient: addr.clone().recipient(),
+                });
+                register_global_called.set(true);
+                Ok(())
+            })?;
+            globals.set("register_global", register_global)?;
+
+            globals.set("self_recipient", self_recipient.to_lua(lua_ctx)?)?;
+
             let lua_handle: Result<Function, LuaError> = globals.get(func_name);
             if let Ok(f) = lua_handle {
-                match f.call::<MultiValue, Value>(args) {
-                    Err(e) => panic!("{:?}", e),
-                    Ok(ret) => Ok(LuaMessage::from_lua(ret, lua_ctx).unwrap()),
+                // `__run` and `__resume` return the id of the coroutine instead of a result
+                // when it's suspended
+                let (ret, suspended) = f.call::<MultiValue, (Value, Option<i64>)>(args)?;
+                match suspended {
+                    Some(tid) => Ok(LuaMessage::ThreadYield(tid.to_string())),
+                    None => Ok(LuaMessage::from_lua(ret, lua_ctx)?),
                 }
             } else {
                 // return nil if handle is not defined
                 Ok(LuaMessage::Nil)
             }
         })
-    })
+    });
+
+    if register_global_called.get() {
+        *registered = true;
+    }
+    res
+}
+
+// the longest period of time a script can wait for, one year.
+// longer ones overflow when added to `Instant::now()`.
+const MAX_DURATION_SECS: f64 = 365.0 * 24.0 * 3600.0;
+
+// a period of time given in seconds by a script
+fn duration(secs: f64) -> Result<Duration, LuaActorError> {
+    match Duration::try_from_secs_f64(secs) {
+        Ok(d) if secs <= MAX_DURATION_SECS => Ok(d),
+        _ => Err(LuaActorError::Conversion(format!(
+            "expected a number of seconds between 0 and {}, got {}",
+            MAX_DURATION_SECS, secs
+        ))),
+    }
+}
+
+// build a child actor and start it on the current arbiter
+fn spawn_child(
+    builder: LuaActorBuilder,
+    parent: &Addr<LuaActor>,
+    children: &mut Vec<Addr<LuaActor>>,
+) -> Result<LuaMessage, LuaActorError> {
+    let mut child = builder.build_child()?;
+    child.parent = Some(parent.clone());
+    let addr = child.start();
+    children.push(addr.clone());
+    Ok(LuaMessage::Recipient(addr.recipient()))
+}
+
+// a recipient is either the name of a recipient added with `add_recipients`, or an address
+fn resolve(
+    recs: &HashMap<String, Recipient<LuaMessage>>,
+    recipient: LuaMessage,
+) -> Result<Recipient<LuaMessage>, LuaActorError> {
+    match recipient {
+        LuaMessage::String(name) => match recs.get(&name) {
+            Some(r) => Ok(r.clone()),
+            None => Err(LuaActorError::MissingRecipient(name)),
+        },
+        LuaMessage::Recipient(r) => Ok(r),
+        r => Err(LuaActorError::Conversion(format!(
+            "expected a recipient name or address, got {:?}",
+            r
+        ))),
+    }
+}
+
+// the recipient a suspended coroutine waits on, for `LuaActor::stats`
+fn describe(recipient: &LuaMessage) -> String {
+    match recipient {
+        LuaMessage::String(name) => name.clone(),
+        _ => "<address>".to_string(),
+    }
+}
+
+// names which are not in the recipient list are looked up in `LuaRegistry` when sending
+fn target(
+    recs: &HashMap<String, Recipient<LuaMessage>>,
+    recipient: LuaMessage,
+) -> Result<Target, LuaActorError> {
+    match resolve(recs, recipient) {
+        Ok(r) => Ok(Target::Address(r)),
+        Err(LuaActorError::MissingRecipient(name)) => Ok(Target::Registry(name)),
+        Err(e) => Err(e),
+    }
 }
 
 impl Actor for LuaActor {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Context<Self>) {
-        if let Err(e) = invoke(
-            &ctx.address().recipient(),
-            ctx,
-            &mut self.vm,
-            &mut self.recipients,
-            "__run",
-            vec![LuaMessage::from("started")],
-        ) {
-            panic!("lua actor started failed {:?}", e);
+        if let Some(ref watcher) = self.watcher {
+            ctx.run_interval(watcher.interval, |act, ctx| act.poll_scripts(ctx));
+        }
+
+        if !self.names.is_empty() {
+            let registry = LuaRegistry::from_registry();
+            for name in self.names.drain(..) {
+                registry.do_send(Register {
+                    name,
+                    recipient: ctx.address().recipient(),
+                });
+            }
+            self.registered = true;
+        }
+
+        if let Some(age) = self.max_suspended_age {
+            let interval = cmp::max(age / 2, Duration::from_millis(1));
+            ctx.run_interval(interval, |act, _| act.evict_suspended());
+        }
+
+        if let Err(e) = invoke(self, ctx, "__run", vec![LuaMessage::from("started")]) {
+            error!("lua actor started failed: {}", e);
         }
+        self.evict_suspended();
     }
 
     fn stopped(&mut self, ctx: &mut Context<Self>) {
-        if let Err(e) = invoke(
-            &ctx.address().recipient(),
-            ctx,
-            &mut self.vm,
-            &mut self.recipients,
-            "__run",
-            vec![LuaMessage::from("stopped")],
-        ) {
-            panic!("lua actor stopped failed {:?}", e);
+        if let Err(e) = invoke(self, ctx, "__run", vec![LuaMessage::from("stopped")]) {
+            error!("lua actor stopped failed: {}", e);
+        }
+        self.persist();
+
+        for child in self.children.drain(..) {
+            child.do_send(StopChild);
+        }
+        if let Some(parent) = self.parent.take() {
+            parent.do_send(ChildStopped(ctx.address()));
+        }
+        if let Some(descendants) = self.spawned.take() {
+            descendants.fetch_sub(1, Ordering::SeqCst);
+        }
+        if self.registered {
+            LuaRegistry::from_registry().do_send(UnregisterAll(ctx.address().recipient()));
         }
     }
 }
 
-struct SendAttempt {
-    recipient_name: String,
-    msg: LuaMessage,
-    cb_thread_id: i64,
+/// Replace the scripts of a running `LuaActor`.
+///
+/// Scripts set to `None` are kept. `ctx.state` and suspended coroutines are preserved,
+/// suspended coroutines keep running the script they were started with.
+///
+/// All scripts are compiled before replacing any of them.
+/// If one of them fails to compile, the actor keeps running the previous version and a
+/// `LuaActorError::Compile` is returned.
+///
+/// `migrate` is run once before the swap with a copy of the current `ctx.state` as its argument.
+/// If it returns a table, the table replaces `ctx.state`, otherwise the copy does:
+///
+/// ```lua
+/// local old_state = ...
+/// return { count = old_state.counter or 0 }
+/// ```
+///
+/// `migrate` runs outside of a coroutine, it can't use the context API.
+/// The new scripts replace the running ones only if `migrate` succeeds.
+/// If it fails, the actor keeps running the previous scripts with the previous `ctx.state`.
+#[derive(Debug, Clone, Default)]
+pub struct ReloadScripts {
+    pub started: Option<String>,
+    pub handle: Option<String>,
+    pub stopped: Option<String>,
+    pub migrate: Option<String>,
 }
 
-impl Message for SendAttempt {
-    type Result = LuaMessage;
+impl Message for ReloadScripts {
+    type Result = Result<(), LuaActorError>;
 }
 
-struct SendAttemptResult {
-    msg: LuaMessage,
-    cb_thread_id: i64,
+impl Handler<ReloadScripts> for LuaActor {
+    type Result = Result<(), LuaActorError>;
+
+    fn handle(&mut self, scripts: ReloadScripts, ctx: &mut Context<Self>) -> Self::Result {
+        self.reload(scripts, ctx)
+    }
 }
 
-impl Message for SendAttemptResult {
-    type Result = LuaMessage;
+/// Get a copy of `ctx.state` of a running `LuaActor`.
+pub struct SnapshotState;
+
+impl Message for SnapshotState {
+    type Result = Result<LuaMessage, LuaActorError>;
 }
 
-impl Handler<LuaMessage> for LuaActor {
-    type Result = LuaMessage;
+impl Handler<SnapshotState> for LuaActor {
+    type Result = Result<LuaMessage, LuaActorError>;
 
-    fn handle(&mut self, msg: LuaMessage, ctx: &mut Context<Self>) -> Self::Result {
-        if let Ok(res) = invoke(
-            &ctx.address().recipient(),
-            ctx,
-            &mut self.vm,
-            &mut self.recipients,
-            "__run",
-            vec![LuaMessage::from("handle"), msg],
-        ) {
-            res
-        } else {
-            LuaMessage::Nil
-        }
+    fn handle(&mut self, _: SnapshotState, _: &mut Context<Self>) -> Self::Result {
+        self.snapshot_state()
     }
 }
 
-impl Handler<SendAttemptResult> for LuaActor {
-    type Result = LuaMessage;
+/// Add a recipient to a running `LuaActor`, like `LuaActor::add_recipients`.
+///
+/// Returns the recipient previously registered with the same name.
+pub struct AddRecipient {
+    pub name: String,
+    pub recipient: Recipient<LuaMessage>,
+}
 
-    fn handle(&mut self, result: SendAttemptResult, ctx: &mut Context<Self>) -> Self::Result {
-        if let Ok(res) = invoke(
-            &ctx.address().recipient(),
-            ctx,
-            &mut self.vm,
-            &mut self.recipients,
-            "__resume",
-            vec![LuaMessage::from(result.cb_thread_id), result.msg],
-        ) {
-            res
-        } else {
-            LuaMessage::Nil
-        }
+impl Message for AddRecipient {
+    type Result = Option<Recipient<LuaMessage>>;
+}
+
+impl Handler<AddRecipient> for LuaActor {
+    type Result = Option<Recipient<LuaMessage>>;
+
+    fn handle(&mut self, msg: AddRecipient, _: &mut Context<Self>) -> Self::Result {
+        self.add_recipients(&msg.name, msg.recipient)
     }
 }
 
-impl Handler<SendAttempt> for LuaActor {
-    type Result = LuaMessage;
+/// Remove the recipient with the given name from a running `LuaActor`.
+///
+/// Returns the removed recipient.
+pub struct RemoveRecipient(pub String);
 
-    fn handle(&mut self, attempt: SendAttempt, ctx: &mut Context<Self>) -> Self::Result {
-        let rec = &self.recipients[&attempt.recipient_name];
-        let self_addr = ctx.address().clone();
-        rec.send(attempt.msg.clone())
-            .into_actor(self)
-            .then(move |res, _, _| {
-                match res {
-                    Ok(msg) => self_addr.do_send(SendAttemptResult {
-                        msg,
-                        cb_thread_id: attempt.cb_thread_id,
-                    }),
-                    _ => {
-                        panic!("send attempt failed: {:?}", res);
-                    }
-                };
-                actix::fut::ok(())
-            })
-            .wait(ctx);
+impl Message for RemoveRecipient {
+    type Result = Option<Recipient<LuaMessage>>;
+}
 
-        LuaMessage::Nil
+impl Handler<RemoveRecipient> for LuaActor {
+    type Result = Option<Recipient<LuaMessage>>;
+
+    fn handle(&mut self, msg: RemoveRecipient, _: &mut Context<Self>) -> Self::Result {
+        self.recipients.remove(&msg.0)
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use futures_timer::Delay;
-    use std::collections::HashMap;
-    use std::time::Duration;
-    use tokio::prelude::Future;
+/// A coroutine suspended in `ctx.send`, `ctx.gather`, `ctx.race` or `ctx.sleep`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SuspendedThread {
+    pub thread_id: i64,
+    /// The time since the coroutine was suspended.
+    pub age: Duration,
+    /// The names of the recipients the coroutine waits on.
+    /// Addresses are shown as `<address>`, the list is empty for `ctx.sleep`.
+    pub waiting_on: Vec<String>,
+}
 
-    use crate::builder::LuaActorBuilder;
+/// Statistics of a `LuaActor`, returned by `LuaActor::stats`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LuaActorStats {
+    /// The suspended coroutines, oldest first.
+    pub suspended: Vec<SuspendedThread>,
+}
 
-    fn lua_actor_with_handle(script: &str) -> LuaActor {
-        LuaActorBuilder::new()
-            .on_handle_with_lua(script)
-            .build()
-            .unwrap()
+/// Query the statistics of a running `LuaActor`.
+pub struct GetStats;
+
+impl Message for GetStats {
+    type Result = LuaActorStats;
+}
+
+impl Handler<GetStats> for LuaActor {
+    type Result = MessageResult<GetStats>;
+
+    fn handle(&mut self, _: GetStats, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.stats())
     }
+}
 
-    #[test]
-    fn lua_actor_basic() {
-        let system = System::new("test");
+/// Query the memory currently used by the lua VM of a running `LuaActor`, in bytes.
+pub struct UsedMemory;
 
-        let lua_addr = lua_actor_with_handle(r#"return ctx.msg + 1"#).start();
+impl Message for UsedMemory {
+    type Result = usize;
+}
 
-        let l = lua_addr.send(LuaMessage::from(1));
-        Arbiter::spawn(
-            l.map(|res| {
-                assert_eq!(res, LuaMessage::from(2));
-                System::current().stop();
-            })
-            .map_err(|e| println!("actor dead {}", e)),
+impl Handler<UsedMemory> for LuaActor {
+    type Result = usize;
+
+    fn handle(&mut self, _: UsedMemory, _: &mut Context<Self>) -> Self::Result {
+        self.used_memory()
+    }
+}
+
+// stop a child when its parent is stopped
+struct StopChild;
+
+impl Message for StopChild {
+    type Result = ();
+}
+
+impl Handler<StopChild> for LuaActor {
+    type Result = ();
+
+    fn handle(&mut self, _: StopChild, ctx: &mut Context<Self>) -> Self::Result {
+        ctx.stop();
+    }
+}
+
+// notify the parent when a child is stopped
+struct ChildStopped(Addr<LuaActor>);
+
+impl Message for ChildStopped {
+    type Result = ();
+}
+
+impl Handler<ChildStopped> for LuaActor {
+    type Result = ();
+
+    fn handle(&mut self, stopped: ChildStopped, ctx: &mut Context<Self>) -> Self::Result {
+        self.children.retain(|child| *child != stopped.0);
+
+        let mut msg = HashMap::new();
+        msg.insert("type".to_string(), LuaMessage::from("child_stopped"));
+        msg.insert(
+            "child".to_string(),
+            LuaMessage::Recipient(stopped.0.recipient()),
+        );
+        // the reply is dropped, nobody is waiting for it
+        let _ = self.handle_message(LuaMessage::from(msg), ctx);
+    }
+}
+
+// the recipient of `ctx.send`, or a name to look up in `LuaRegistry`
+enum Target {
+    Address(Recipient<LuaMessage>),
+    Registry(String),
+}
+
+// find a name which is not in the recipients of the actor in `LuaRegistry`
+fn lookup(name: String) -> impl Future<Item = Recipient<LuaMessage>, Error = LuaActorError> {
+    LuaRegistry::from_registry()
+        .send(Get(name.clone()))
+        .map_err(LuaActorError::Mailbox)
+        .and_then(|found| found.ok_or(LuaActorError::MissingRecipient(name)))
+}
+
+// `ctx.do_send` to a name found in `LuaRegistry`. the script doesn't wait for the lookup,
+// errors are logged
+fn forward(name: String, msg: LuaMessage) {
+    Arbiter::spawn(
+        lookup(name)
+            .and_then(|r| {
+                r.do_send(msg)
+                    .map_err(|_| LuaActorError::Mailbox(MailboxError::Closed))
+            })
+            .map_err(|e| error!("lua actor do_send failed: {}", e)),
+    );
+}
+
+// send `msg` to `target` and wait for the reply
+fn deliver(
+    target: Target,
+    msg: LuaMessage,
+) -> impl Future<Item = LuaMessage, Error = LuaActorError> {
+    match target {
+        Target::Address(r) => Either::A(r.send(msg).map_err(LuaActorError::Mailbox)),
+        Target::Registry(name) => {
+            Either::B(lookup(name).and_then(|r| r.send(msg).map_err(LuaActorError::Mailbox)))
+        }
+    }
+}
+
+struct SendAttempt {
+    recipient: Target,
+    msg: LuaMessage,
+    timeout: Option<Duration>,
+    cb_thread_id: i64,
+}
+
+impl Message for SendAttempt {
+    type Result = LuaMessage;
+}
+
+struct SendAttemptResult {
+    msg: Result<LuaMessage, LuaActorError>,
+    cb_thread_id: i64,
+}
+
+impl Message for SendAttemptResult {
+    type Result = ();
+}
+
+impl LuaActor {
+    fn handle_message(&mut self, msg: LuaMessage, ctx: &mut Context<Self>) -> LuaReply {
+        let res = invoke(self, ctx, "__run", vec![LuaMessage::from("handle"), msg]);
+        if let Some(ref mut persistence) = self.persistence {
+            if persistence.handled() {
+                self.persist();
+            }
+        }
+        match res {
+            Ok(LuaMessage::ThreadYield(tid)) => {
+                // the coroutine is suspended in `ctx.send` or `ctx.sleep`.
+                // reply once it's resumed and returned in `Handler<SendAttemptResult>`
+                let (tx, rx) = oneshot::channel();
+                if let Ok(tid) = tid.parse() {
+                    self.replies.insert(tid, tx);
+                }
+                self.evict_suspended();
+                LuaReply::Deferred(rx)
+            }
+            res => LuaReply::Ready(res),
+        }
+    }
+}
+
+/// Replies with `LuaMessage::Nil` if the handler fails, the error is only logged.
+/// Send a `LuaCall` to receive the error.
+impl Handler<LuaMessage> for LuaActor {
+    type Result = LuaReply;
+
+    fn handle(&mut self, msg: LuaMessage, ctx: &mut Context<Self>) -> Self::Result {
+        self.handle_message(msg, ctx)
+    }
+}
+
+impl Handler<LuaCall> for LuaActor {
+    type Result = ResponseFuture<LuaMessage, LuaActorError>;
+
+    fn handle(&mut self, call: LuaCall, ctx: &mut Context<Self>) -> Self::Result {
+        self.handle_message(call.0, ctx).into_future()
+    }
+}
+
+impl Handler<SendAttemptResult> for LuaActor {
+    type Result = ();
+
+    fn handle(&mut self, result: SendAttemptResult, ctx: &mut Context<Self>) -> Self::Result {
+        if self.suspended.remove(&result.cb_thread_id).is_none() {
+            // the coroutine was evicted
+            return;
+        }
+        // `ctx.send` returns the error, the other functions raise it
+        let args = match result.msg {
+            Ok(msg) => vec![LuaMessage::from(result.cb_thread_id), msg],
+            Err(e) => {
+                let args = vec![
+                    LuaMessage::from(result.cb_thread_id),
+                    LuaMessage::Nil,
+                    LuaMessage::from(e.to_string()),
+                ];
+                self.resume_error = Some(e);
+                args
+            }
+        };
+        let res = invoke(self, ctx, "__resume", args);
+        self.resume_error = None;
+        match res {
+            // suspended again by another `ctx.send` or `ctx.sleep`, keep waiting
+            Ok(LuaMessage::ThreadYield(_)) => self.evict_suspended(),
+            res => {
+                if let Some(tx) = self.replies.remove(&result.cb_thread_id) {
+                    // the receiver is gone if the caller doesn't wait for the response
+                    let _ = tx.send(res);
+                }
+            }
+        }
+    }
+}
+
+impl Handler<SendAttempt> for LuaActor {
+    type Result = LuaMessage;
+
+    fn handle(&mut self, attempt: SendAttempt, ctx: &mut Context<Self>) -> Self::Result {
+        let self_addr = ctx.address().clone();
+        let cb_thread_id = attempt.cb_thread_id;
+        let reply = deliver(attempt.recipient, attempt.msg);
+        let reply = match attempt.timeout {
+            Some(timeout) => Either::A(Timeout::new(reply, timeout).map_err(|e| {
+                e.into_inner()
+                    .unwrap_or(LuaActorError::Mailbox(MailboxError::Timeout))
+            })),
+            None => Either::B(reply),
+        };
+        // errors are returned by `ctx.send` in the resumed coroutine
+        reply
+            .into_actor(self)
+            .then(move |msg, _, _| {
+                self_addr.do_send(SendAttemptResult { msg, cb_thread_id });
+                actix::fut::ok(())
+            })
+            .spawn(ctx);
+
+        LuaMessage::Nil
+    }
+}
+
+// `ctx.gather` and `ctx.race`, all the requests are sent at once
+struct GatherAttempt {
+    requests: Vec<(Target, LuaMessage)>,
+    race: bool,
+    cb_thread_id: i64,
+}
+
+impl Message for GatherAttempt {
+    type Result = LuaMessage;
+}
+
+impl Handler<GatherAttempt> for LuaActor {
+    type Result = LuaMessage;
+
+    fn handle(&mut self, attempt: GatherAttempt, ctx: &mut Context<Self>) -> Self::Result {
+        let self_addr = ctx.address().clone();
+        let cb_thread_id = attempt.cb_thread_id;
+        let replies = attempt
+            .requests
+            .into_iter()
+            .map(|(target, msg)| deliver(target, msg));
+        let res = if attempt.race {
+            // resumed with the first reply and its index, or the last error if all failed
+            Either::A(
+                future::select_ok(replies.enumerate().map(|(i, reply)| {
+                    reply.map(move |msg| LuaMessage::from(vec![msg, LuaMessage::from(i + 1)]))
+                }))
+                .map(|(msg, _)| msg),
+            )
+        } else {
+            // resumed with the replies and the errors, in the order of the requests
+            Either::B(future::join_all(replies.map(|reply| reply.then(Ok))).map(
+                |results: Vec<Result<LuaMessage, LuaActorError>>| {
+                    let mut replies = vec![];
+                    let mut errors = vec![];
+                    for res in results {
+                        match res {
+                            Ok(msg) => {
+                                replies.push(msg);
+                                errors.push(LuaMessage::Nil);
+                            }
+                            Err(e) => {
+                                replies.push(LuaMessage::Nil);
+                                errors.push(LuaMessage::from(e.to_string()));
+                            }
+                        }
+                    }
+                    LuaMessage::from(vec![LuaMessage::from(replies), LuaMessage::from(errors)])
+                },
+            ))
+        };
+        res.into_actor(self)
+            .then(move |msg, _, _| {
+                self_addr.do_send(SendAttemptResult { msg, cb_thread_id });
+                actix::fut::ok(())
+            })
+            .spawn(ctx);
+
+        LuaMessage::Nil
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_timer::Delay;
+    use std::collections::HashMap;
+    use std::time::Duration;
+    use tokio::prelude::Future;
+
+    use crate::builder::LuaActorBuilder;
+    use crate::registry::Lookup;
+    use crate::sandbox::SandboxProfile;
+
+    fn lua_actor_with_handle(script: &str) -> LuaActor {
+        LuaActorBuilder::new()
+            .on_handle_with_lua(script)
+            .build()
+            .unwrap()
+    }
+
+    // an actor which never replies
+    #[derive(Default)]
+    struct Silent(Vec<oneshot::Sender<Result<LuaMessage, LuaActorError>>>);
+
+    impl Actor for Silent {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<LuaMessage> for Silent {
+        type Result = LuaReply;
+
+        fn handle(&mut self, _: LuaMessage, _ctx: &mut Context<Self>) -> Self::Result {
+            let (tx, rx) = oneshot::channel();
+            self.0.push(tx);
+            LuaReply::Deferred(rx)
+        }
+    }
+
+    #[test]
+    fn lua_actor_basic() {
+        let system = System::new("test");
+
+        let lua_addr = lua_actor_with_handle(r#"return ctx.msg + 1"#).start();
+
+        let l = lua_addr.send(LuaMessage::from(1));
+        Arbiter::spawn(
+            l.map(|res| {
+                assert_eq!(res, LuaMessage::from(2));
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_syntax_error() {
+        let res = LuaActorBuilder::new()
+            .on_handle_with_lua(r"return 1+")
+            .build();
+
+        if res.is_ok() {
+            panic!("should return Err(syntax_error)");
+        }
+    }
+
+    #[test]
+    fn lua_actor_user_error() {
+        let system = System::new("test");
+
+        let lua_addr = lua_actor_with_handle(
+            r#"
+        if ctx.msg == 0 then
+            error("foo")
+        end
+        return ctx.msg
+        "#,
+        )
+        .start();
+
+        let l = lua_addr.send(LuaMessage::from(0));
+        Arbiter::spawn(
+            l.map(move |res| {
+                assert_eq!(res, LuaMessage::Nil);
+                // the actor should keep running after a handler error
+                let l2 = lua_addr.send(LuaMessage::from(1));
+                Arbiter::spawn(
+                    l2.map(|res| {
+                        assert_eq!(res, LuaMessage::from(1));
+                        System::current().stop();
+                    })
+                    .map_err(|e| println!("actor dead {}", e)),
+                );
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_missing_recipient() {
+        let system = System::new("test");
+
+        let lua_addr = lua_actor_with_handle(
+            r#"
+        if ctx.msg == 0 then
+            ctx.do_send("nobody", "Hello")
+        end
+        return ctx.msg
+        "#,
+        )
+        .start();
+
+        let l = lua_addr.send(LuaMessage::from(0));
+        Arbiter::spawn(
+            l.map(move |res| {
+                // the unknown name is only logged
+                assert_eq!(res, LuaMessage::from(0));
+                let l2 = lua_addr.send(LuaMessage::from(1));
+                Arbiter::spawn(
+                    l2.map(|res| {
+                        assert_eq!(res, LuaMessage::from(1));
+                        System::current().stop();
+                    })
+                    .map_err(|e| println!("actor dead {}", e)),
+                );
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_missing_recipient_error() {
+        let system = System::new("test");
+
+        let lua_addr = lua_actor_with_handle(
+            r#"
+        -- neither waits for the registry nor raises, the error is logged
+        ctx.do_send("nobody", "Hello")
+        local res, err = ctx.send("nobody", "Hello")
+        return err
+        "#,
+        )
+        .start();
+
+        let l = lua_addr.send(LuaCall(LuaMessage::Nil));
+        Arbiter::spawn(
+            l.map(|res| {
+                // `ctx.send` returns the error
+                assert_eq!(
+                    res.unwrap(),
+                    LuaMessage::from("recipient not found: nobody")
+                );
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_call() {
+        let system = System::new("test");
+
+        let lua_addr = lua_actor_with_handle(
+            r#"
+        if ctx.msg == 0 then
+            error("foo")
+        end
+        return ctx.msg
+        "#,
+        )
+        .start();
+
+        let l = lua_addr.send(LuaCall(LuaMessage::from(0)));
+        Arbiter::spawn(
+            l.map(move |res| {
+                match res {
+                    Err(LuaActorError::Runtime { message, .. }) => {
+                        assert!(message.ends_with("foo"));
+                    }
+                    _ => panic!("should return runtime error"),
+                }
+                let l2 = lua_addr.send(LuaCall(LuaMessage::from(1)));
+                Arbiter::spawn(
+                    l2.map(|res| {
+                        assert_eq!(res.unwrap(), LuaMessage::from(1));
+                        System::current().stop();
+                    })
+                    .map_err(|e| println!("actor dead {}", e)),
+                );
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_traceback() {
+        let system = System::new("test");
+
+        let lua_addr = lua_actor_with_handle(
+            r#"
+        local function fail()
+            error("foo")
+        end
+        fail()
+        "#,
+        )
+        .start();
+
+        let l = lua_addr.send(LuaCall(LuaMessage::Nil));
+        Arbiter::spawn(
+            l.map(|res| {
+                match res {
+                    Err(LuaActorError::Runtime { traceback, .. }) => {
+                        assert!(traceback.contains("in local 'fail'"));
+                        assert!(!traceback.contains("Prelude"));
+                    }
+                    _ => panic!("should return runtime error"),
+                }
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_call_thread_yield_error() {
+        struct Callback;
+        impl Actor for Callback {
+            type Context = Context<Self>;
+        }
+
+        impl Handler<LuaMessage> for Callback {
+            type Result = LuaMessage;
+
+            fn handle(&mut self, _: LuaMessage, _ctx: &mut Context<Self>) -> Self::Result {
+                LuaMessage::Nil
+            }
+        }
+
+        let system = System::new("test");
+
+        let mut actor = lua_actor_with_handle(
+            r#"
+            local result = ctx.send("callback", "Hello")
+            error("bar")
+            "#,
+        );
+        actor.add_recipients("callback", Callback.start().recipient());
+        let addr = actor.start();
+
+        let l = addr.send(LuaCall(LuaMessage::Nil));
+        Arbiter::spawn(
+            l.map(|res| {
+                match res {
+                    Err(LuaActorError::Runtime { message, .. }) => {
+                        assert!(message.ends_with("bar"));
+                    }
+                    _ => panic!("should return runtime error"),
+                }
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_instruction_limit() {
+        let system = System::new("test");
+
+        let lua_addr = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+            if ctx.msg == 0 then
+                while true do end
+            end
+            return ctx.msg
+            "#,
+            )
+            .max_instructions_per_message(10000)
+            .build()
+            .unwrap()
+            .start();
+
+        let l = lua_addr.send(LuaCall(LuaMessage::from(0)));
+        Arbiter::spawn(
+            l.map(move |res| {
+                match res {
+                    Err(LuaActorError::InstructionLimit) => (),
+                    _ => panic!("should return instruction limit error"),
+                }
+                // the budget is reset for the next message
+                let l2 = lua_addr.send(LuaCall(LuaMessage::from(1)));
+                Arbiter::spawn(
+                    l2.map(|res| {
+                        assert_eq!(res.unwrap(), LuaMessage::from(1));
+                        System::current().stop();
+                    })
+                    .map_err(|e| println!("actor dead {}", e)),
+                );
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_limit_pcall() {
+        let system = System::new("test");
+
+        let lua_addr = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+            local function spin() while true do end end
+            if ctx.msg == 0 then
+                for i = 1, 50 do pcall(spin) end
+                return 50
+            elseif ctx.msg == 1 then
+                while true do xpcall(spin, function (e) return e end) end
+            else
+                while true do coroutine.resume(coroutine.create(spin)) end
+            end
+            "#,
+            )
+            .max_instructions_per_message(10000)
+            .handler_timeout(Duration::from_millis(200))
+            .build()
+            .unwrap()
+            .start();
+
+        let l0 = lua_addr.send(LuaCall(LuaMessage::from(0)));
+        let l1 = lua_addr.send(LuaCall(LuaMessage::from(1)));
+        let l2 = lua_addr.send(LuaCall(LuaMessage::from(2)));
+        Arbiter::spawn(
+            l0.join3(l1, l2)
+                .map(|(r0, r1, r2)| {
+                    // scripts can't catch the limit errors to keep running
+                    for res in &[r0, r1, r2] {
+                        match res {
+                            Err(LuaActorError::InstructionLimit) => (),
+                            _ => panic!("should return instruction limit error"),
+                        }
+                    }
+                    System::current().stop();
+                })
+                .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_handler_timeout() {
+        let system = System::new("test");
+
+        let lua_addr = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+            while true do end
+            "#,
+            )
+            .handler_timeout(Duration::from_millis(100))
+            .build()
+            .unwrap()
+            .start();
+
+        let l = lua_addr.send(LuaCall(LuaMessage::Nil));
+        Arbiter::spawn(
+            l.map(|res| {
+                match res {
+                    Err(LuaActorError::Timeout) => (),
+                    _ => panic!("should return timeout error"),
+                }
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_memory_limit() {
+        let system = System::new("test");
+
+        let lua_addr = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+            if ctx.msg == 0 then
+                local t = {}
+                for i = 1, 1000000 do
+                    t[i] = string.rep("x", 100) .. i
+                end
+            end
+            return ctx.msg
+            "#,
+            )
+            .memory_limit(1024 * 1024)
+            .build()
+            .unwrap()
+            .start();
+
+        let l = lua_addr.send(LuaCall(LuaMessage::from(0)));
+        Arbiter::spawn(
+            l.map(move |res| {
+                match res {
+                    Err(LuaActorError::OutOfMemory) => (),
+                    _ => panic!("should return out of memory error"),
+                }
+                let l2 = lua_addr.send(LuaCall(LuaMessage::from(1)));
+                Arbiter::spawn(
+                    l2.map(move |res| {
+                        assert_eq!(res.unwrap(), LuaMessage::from(1));
+                        let l3 = lua_addr.send(UsedMemory);
+                        Arbiter::spawn(
+                            l3.map(|used| {
+                                assert!(used > 0);
+                                assert!(used <= 1024 * 1024);
+                                System::current().stop();
+                            })
+                            .map_err(|e| println!("actor dead {}", e)),
+                        );
+                    })
+                    .map_err(|e| println!("actor dead {}", e)),
+                );
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_sandbox() {
+        let system = System::new("test");
+
+        let lua_addr = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+            if ctx.msg == 0 then
+                os.execute("echo pwned")
+            end
+            return string.rep("x", ctx.msg)
+            "#,
+            )
+            .sandbox(SandboxProfile::pure_compute())
+            .build()
+            .unwrap()
+            .start();
+
+        let l = lua_addr.send(LuaCall(LuaMessage::from(0)));
+        Arbiter::spawn(
+            l.map(move |res| {
+                match res {
+                    Err(LuaActorError::Runtime { message, .. }) => {
+                        assert!(message.contains("os"));
+                    }
+                    _ => panic!("should return runtime error"),
+                }
+                let l2 = lua_addr.send(LuaCall(LuaMessage::from(3)));
+                Arbiter::spawn(
+                    l2.map(|res| {
+                        assert_eq!(res.unwrap(), LuaMessage::from("xxx"));
+                        System::current().stop();
+                    })
+                    .map_err(|e| println!("actor dead {}", e)),
+                );
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_reload_scripts() {
+        let system = System::new("test");
+
+        let addr = lua_actor_with_handle(
+            r#"
+        ctx.state.x = (ctx.state.x or 0) + 1
+        return ctx.state.x
+        "#,
+        )
+        .start();
+
+        let l = addr
+            .send(LuaMessage::Nil)
+            .and_then({
+                let addr = addr.clone();
+                move |res| {
+                    assert_eq!(res, LuaMessage::from(1));
+                    // compile error keeps the previous version
+                    addr.send(ReloadScripts {
+                        handle: Some("return 1 +".to_string()),
+                        ..Default::default()
+                    })
+                }
+            })
+            .and_then({
+                let addr = addr.clone();
+                move |res| {
+                    match res {
+                        Err(LuaActorError::Compile(_)) => (),
+                        _ => panic!("should return compile error"),
+                    }
+                    addr.send(LuaMessage::Nil)
+                }
+            })
+            .and_then({
+                let addr = addr.clone();
+                move |res| {
+                    assert_eq!(res, LuaMessage::from(2));
+                    addr.send(ReloadScripts {
+                        handle: Some(
+                            r#"
+                        ctx.state.y = ctx.state.y + 10
+                        return ctx.state.y
+                        "#
+                            .to_string(),
+                        ),
+                        migrate: Some(
+                            r#"
+                        local old_state = ...
+                        return { y = old_state.x }
+                        "#
+                            .to_string(),
+                        ),
+                        ..Default::default()
+                    })
+                }
+            })
+            .and_then({
+                let addr = addr.clone();
+                move |res| {
+                    res.unwrap();
+                    addr.send(LuaMessage::Nil)
+                }
+            })
+            .and_then({
+                let addr = addr.clone();
+                move |res| {
+                    assert_eq!(res, LuaMessage::from(12));
+                    // a failed migration keeps the previous version and state
+                    addr.send(ReloadScripts {
+                        handle: Some("return 0".to_string()),
+                        migrate: Some(
+                            r#"
+                        local state = ...
+                        state.y = 1000
+                        error("migrate failed")
+                        "#
+                            .to_string(),
+                        ),
+                        ..Default::default()
+                    })
+                }
+            })
+            .and_then(move |res| {
+                match res {
+                    Err(LuaActorError::Runtime { message, .. }) => {
+                        assert!(message.ends_with("migrate failed"))
+                    }
+                    _ => panic!("should return runtime error"),
+                }
+                addr.send(LuaMessage::Nil)
+            })
+            .map(|res| {
+                assert_eq!(res, LuaMessage::from(22));
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e));
+        Arbiter::spawn(l);
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_watch() {
+        use std::fs;
+        use std::thread;
+
+        let system = System::new("test");
+
+        let path = env::temp_dir().join(format!("actix-lua-watch-{}.lua", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        fs::write(&path, "return 1").unwrap();
+
+        let addr = LuaActorBuilder::new()
+            .on_handle(&path)
+            .watch(true)
+            .watch_interval(Duration::from_millis(50))
+            .build()
+            .unwrap()
+            .start();
+
+        let l = addr
+            .send(LuaMessage::Nil)
+            .and_then(move |res| {
+                assert_eq!(res, LuaMessage::from(1));
+                // make sure the modified time changes
+                thread::sleep(Duration::from_millis(10));
+                fs::write(&path, "return 2").unwrap();
+                Delay::new(Duration::from_millis(200))
+                    .map_err(|_| MailboxError::Closed)
+                    .and_then(move |_| addr.send(LuaMessage::Nil))
+                    .map(move |res| {
+                        fs::remove_file(&path).unwrap();
+                        res
+                    })
+            })
+            .map(|res| {
+                assert_eq!(res, LuaMessage::from(2));
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e));
+        Arbiter::spawn(l);
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_initial_state() {
+        let system = System::new("test");
+
+        let mut state = HashMap::new();
+        state.insert("x".to_string(), LuaMessage::from(41));
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+            ctx.state.x = ctx.state.x + 1
+            return ctx.state.x
+            "#,
+            )
+            .initial_state(LuaMessage::from(state))
+            .build()
+            .unwrap()
+            .start();
+
+        let l = addr
+            .send(LuaMessage::Nil)
+            .and_then(move |res| {
+                assert_eq!(res, LuaMessage::from(42));
+                addr.send(SnapshotState)
+            })
+            .map(|res| {
+                let mut state = HashMap::new();
+                state.insert("x".to_string(), LuaMessage::from(42));
+                assert_eq!(res.unwrap(), LuaMessage::from(state));
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e));
+        Arbiter::spawn(l);
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_persist_state() {
+        use crate::persist::PersistPolicy;
+        use std::fs;
+
+        let system = System::new("test");
+
+        let path = env::temp_dir().join(format!("actix-lua-state-{}", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        fn build(path: &str) -> LuaActor {
+            LuaActorBuilder::new()
+                .on_handle_with_lua(
+                    r#"
+                ctx.state.x = (ctx.state.x or 0) + 1
+                return ctx.state.x
+                "#,
+                )
+                .persist_state(path, PersistPolicy::EveryMessages(1))
+                .build()
+                .unwrap()
+        }
+
+        let addr = build(&path).start();
+        let l = addr.send(LuaMessage::Nil).map(move |res| {
+            assert_eq!(res, LuaMessage::from(1));
+
+            // the state is restored from the state file
+            let actor = build(&path);
+            let mut state = HashMap::new();
+            state.insert("x".to_string(), LuaMessage::from(1));
+            assert_eq!(actor.snapshot_state().unwrap(), LuaMessage::from(state));
+
+            fs::remove_file(&path).unwrap();
+            System::current().stop();
+        });
+        Arbiter::spawn(l.map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_persist_array_state() {
+        use crate::persist::PersistPolicy;
+        use std::fs;
+
+        let system = System::new("test");
+
+        let path = env::temp_dir().join(format!("actix-lua-array-state-{}", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        fn build(path: &str) -> LuaActor {
+            LuaActorBuilder::new()
+                .on_handle_with_lua(
+                    r#"
+                ctx.state[1] = (ctx.state[1] or 0) + 1
+                return ctx.state[1]
+                "#,
+                )
+                .persist_state(path, PersistPolicy::EveryMessages(1))
+                .build()
+                .unwrap()
+        }
+
+        let addr = build(&path).start();
+        let l = addr.send(LuaMessage::Nil).map(move |res| {
+            assert_eq!(res, LuaMessage::from(1));
+
+            // a state with only integer keys is saved as an array
+            let actor = build(&path);
+            assert_eq!(actor.snapshot_state().unwrap(), LuaMessage::from(vec![1]));
+
+            fs::remove_file(&path).unwrap();
+            System::current().stop();
+        });
+        Arbiter::spawn(l.map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn lua_actor_json() {
+        let system = System::new("test");
+
+        let lua_addr = lua_actor_with_handle(
+            r#"
+        local t = require("json").decode(ctx.msg)
+        t.x = t.x + 1
+        return json.encode(t)
+        "#,
+        )
+        .start();
+
+        let l = lua_addr.send(LuaMessage::from(r#"{"x": 1}"#));
+        Arbiter::spawn(
+            l.map(|res| {
+                assert_eq!(res, LuaMessage::from(r#"{"x":2}"#));
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_return_table() {
+        let system = System::new("test");
+
+        let lua_addr = lua_actor_with_handle(
+            r#"
+        return {x = 1}
+        "#,
+        )
+        .start();
+
+        let l = lua_addr.send(LuaMessage::from(3));
+        Arbiter::spawn(
+            l.map(|res| {
+                let mut t = HashMap::new();
+                t.insert("x".to_string(), LuaMessage::from(1));
+
+                assert_eq!(res, LuaMessage::from(t));
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_state() {
+        let system = System::new("test");
+
+        let lua_addr = lua_actor_with_handle(
+            r#"
+        if not ctx.state.x then ctx.state.x = 0 end
+
+        ctx.state.x = ctx.state.x + 1
+        return ctx.state.x
+        "#,
+        )
+        .start();
+
+        let l = lua_addr.send(LuaMessage::Nil);
+        Arbiter::spawn(
+            l.map(move |res| {
+                assert_eq!(res, LuaMessage::from(1));
+                let l2 = lua_addr.send(LuaMessage::Nil);
+                Arbiter::spawn(
+                    l2.map(|res| {
+                        assert_eq!(res, LuaMessage::from(2));
+                        System::current().stop();
+                    })
+                    .map_err(|e| println!("actor dead {}", e)),
+                );
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_notify() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_started_with_lua(
+                r#"
+            ctx.notify(100)
+            "#,
+            )
+            .on_handle_with_lua(
+                r#"
+            if ctx.msg == 100 then
+                ctx.state.notified = ctx.msg
+            end
+
+            return ctx.msg + ctx.state.notified
+            "#,
+            )
+            .build()
+            .unwrap()
+            .start();
+
+        let delay = Delay::new(Duration::from_secs(1)).map(move |()| {
+            let l = addr.send(LuaMessage::from(1));
+            Arbiter::spawn(
+                l.map(|res| {
+                    assert_eq!(res, LuaMessage::from(101));
+                    System::current().stop();
+                })
+                .map_err(|e| println!("actor dead {}", e)),
+            )
+        });
+        Arbiter::spawn(delay.map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_notify_later() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_started_with_lua(
+                r#"
+            ctx.notify_later(100, 1)
+            "#,
+            )
+            .on_handle_with_lua(
+                r#"
+            if ctx.msg == 100 then
+                ctx.state.notified = ctx.msg
+            end
+
+            return ctx.msg + ctx.state.notified
+            "#,
+            )
+            .build()
+            .unwrap()
+            .start();
+
+        let delay = Delay::new(Duration::from_secs(2)).map(move |()| {
+            let l2 = addr.send(LuaMessage::from(1));
+            Arbiter::spawn(
+                l2.map(|res| {
+                    assert_eq!(res, LuaMessage::from(101));
+                    System::current().stop();
+                })
+                .map_err(|e| println!("actor dead {}", e)),
+            )
+        });
+        Arbiter::spawn(delay.map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_send_error() {
+        let system = System::new("test");
+
+        let mut actor = lua_actor_with_handle(
+            r#"
+            if ctx.msg == "timeout" then
+                local res, err = ctx.send("silent", "hi", { timeout = 0.1 })
+                return { res == nil, err }
+            elseif ctx.msg == "threads" then
+                local n = 0
+                for _ in pairs(__threads) do
+                    n = n + 1
+                end
+                return n
+            end
+            local res, err = ctx.send(ctx.msg.closed, "hi")
+            return { res == nil, err }
+            "#,
+        );
+        actor.add_recipients("silent", Silent::default().start().recipient());
+        let lua_addr = actor.start();
+
+        let closed = LuaActorBuilder::new()
+            .on_started_with_lua("ctx.terminate()")
+            .build()
+            .unwrap()
+            .start();
+        let mut t = HashMap::new();
+        t.insert("closed".to_string(), LuaMessage::from(closed.recipient()));
+
+        let l = lua_addr.send(LuaMessage::from("timeout"));
+        Arbiter::spawn(
+            l.and_then(move |res| {
+                assert_eq!(
+                    res,
+                    LuaMessage::from(vec![
+                        LuaMessage::from(true),
+                        LuaMessage::from("mailbox error: Message delivery timed out"),
+                    ])
+                );
+                // the suspended coroutine is cleaned up
+                lua_addr
+                    .send(LuaMessage::from("threads"))
+                    .map(move |res| (lua_addr, res))
+            })
+            .and_then(|(lua_addr, res)| {
+                assert_eq!(res, LuaMessage::from(0));
+                lua_addr.send(LuaMessage::from(t))
+            })
+            .map(|res| {
+                assert_eq!(
+                    res,
+                    LuaMessage::from(vec![
+                        LuaMessage::from(true),
+                        LuaMessage::from("mailbox error: Mailbox has closed"),
+                    ])
+                );
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_pending_send() {
+        let system = System::new("test");
+
+        let mut actor = lua_actor_with_handle(
+            r#"
+            if ctx.msg == "wait" then
+                return ctx.send("silent", "hi")
+            end
+            return ctx.msg
+            "#,
+        );
+        actor.add_recipients("silent", Silent::default().start().recipient());
+        let lua_addr = actor.start();
+
+        // a send without timeout never resolves, the actor keeps handling other messages
+        lua_addr.do_send(LuaMessage::from("wait"));
+        let l = Delay::new(Duration::from_millis(100))
+            .map_err(|_| MailboxError::Timeout)
+            .and_then(move |_| lua_addr.send(LuaMessage::from("ping")));
+        Arbiter::spawn(
+            l.map(|res| {
+                assert_eq!(res, LuaMessage::from("ping"));
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_suspended_string() {
+        let system = System::new("test");
+
+        let mut actor = lua_actor_with_handle(
+            r#"
+            if ctx.msg == "wait" then
+                return ctx.send("silent", "hi")
+            end
+            return "__suspended__0"
+            "#,
+        );
+        actor.add_recipients("silent", Silent::default().start().recipient());
+        let lua_addr = actor.start();
+
+        // coroutine 0 is suspended, a string looking like its id is still a string
+        lua_addr.do_send(LuaMessage::from("wait"));
+        let l = Delay::new(Duration::from_millis(100))
+            .map_err(|_| MailboxError::Timeout)
+            .and_then(move |_| lua_addr.send(LuaMessage::from("string")));
+        Arbiter::spawn(
+            l.map(|res| {
+                assert_eq!(res, LuaMessage::from("__suspended__0"));
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_stats() {
+        let system = System::new("test");
+
+        let mut actor = LuaActorBuilder::new()
+            .on_handle_with_lua(r#"return ctx.send("silent", ctx.msg)"#)
+            .max_suspended_threads(2)
+            .build()
+            .unwrap();
+        actor.add_recipients("silent", Silent::default().start().recipient());
+        let lua_addr = actor.start();
+        let sleeper = LuaActorBuilder::new()
+            .on_handle_with_lua(r#"ctx.sleep(10)"#)
+            .max_suspended_age(Duration::from_millis(100))
+            .build()
+            .unwrap()
+            .start();
+
+        let first = lua_addr.send(LuaCall(LuaMessage::from(1)));
+        lua_addr.do_send(LuaMessage::from(2));
+        let l = lua_addr.send(GetStats);
+        Arbiter::spawn(
+            l.and_then(move |stats| {
+                assert_eq!(stats.suspended.len(), 2);
+                assert_eq!(stats.suspended[0].waiting_on, vec!["silent"]);
+                assert!(stats.suspended[0].age >= stats.suspended[1].age);
+                // the oldest coroutine is evicted
+                lua_addr.do_send(LuaMessage::from(3));
+                first
+                    .map(|res| assert!(matches!(res, Err(LuaActorError::Evicted))))
+                    .and_then(move |_| lua_addr.send(GetStats))
+            })
+            .and_then(move |stats| {
+                assert_eq!(stats.suspended.len(), 2);
+                sleeper.send(LuaCall(LuaMessage::Nil))
+            })
+            .map(|res| {
+                // evicted by the age limit
+                assert!(matches!(res, Err(LuaActorError::Evicted)));
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_pending_gather() {
+        let system = System::new("test");
+
+        let mut actor = lua_actor_with_handle(
+            r#"
+            if ctx.msg == "gather" then
+                ctx.gather({ { "silent", 1 } })
+            elseif ctx.msg == "race" then
+                ctx.race({ { "silent", 1 }, { "silent", 2 } })
+            end
+            ctx.state.n = (ctx.state.n or 0) + 1
+            return ctx.state.n
+            "#,
+        );
+        actor.add_recipients("silent", Silent::default().start().recipient());
+        let lua_addr = actor.start();
+
+        // the suspended coroutines don't block the messages handled after them
+        lua_addr.do_send(LuaMessage::from("gather"));
+        lua_addr.do_send(LuaMessage::from("race"));
+        let l = Delay::new(Duration::from_millis(100))
+            .map_err(|_| MailboxError::Timeout)
+            .and_then(move |_| lua_addr.send(LuaMessage::from("bump")));
+        Arbiter::spawn(
+            l.map(|res| {
+                assert_eq!(res, LuaMessage::from(1));
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_gather() {
+        use std::time::Instant;
+
+        let system = System::new("test");
+
+        let worker = || {
+            lua_actor_with_handle(
+                r#"
+                ctx.sleep(ctx.msg.delay)
+                return ctx.msg.value * 2
+                "#,
+            )
+            .start()
+            .recipient()
+        };
+        let mut actor = lua_actor_with_handle(
+            r#"
+            if ctx.msg == "gather" then
+                local replies, errors = ctx.gather({
+                    { "a", { value = 1, delay = 0.2 } },
+                    { "nobody", {} },
+                    { "b", { value = 3, delay = 0.2 } },
+                })
+                return { replies[1], replies[3], errors[2] ~= nil, errors[1] == nil }
+            else
+                local reply, i = ctx.race({
+                    { "a", { value = 1, delay = 0.3 } },
+                    { "b", { value = 2, delay = 0.05 } },
+                })
+                return { reply, i }
+            end
+            "#,
+        );
+        actor.add_recipients("a", worker());
+        actor.add_recipients("b", worker());
+        let lua_addr = actor.start();
+
+        let start = Instant::now();
+        let l = lua_addr.send(LuaMessage::from("gather"));
+        Arbiter::spawn(
+            l.and_then(move |res| {
+                assert_eq!(
+                    res,
+                    LuaMessage::from(vec![
+                        LuaMessage::from(2),
+                        LuaMessage::from(6),
+                        LuaMessage::from(true),
+                        LuaMessage::from(true),
+                    ])
+                );
+                // the requests are sent at once
+                assert!(start.elapsed() < Duration::from_millis(400));
+                lua_addr.send(LuaMessage::from("race"))
+            })
+            .map(|res| {
+                assert_eq!(
+                    res,
+                    LuaMessage::from(vec![LuaMessage::from(4), LuaMessage::from(2)])
+                );
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_sleep() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+        use std::time::Instant;
+
+        let system = System::new("test");
+
+        let lua_addr = lua_actor_with_handle(
+            r#"
+            if ctx.msg == "slow" then
+                ctx.sleep(0.2)
+                return "slept"
+            end
+            return ctx.msg
+            "#,
+        )
+        .start();
+
+        let start = Instant::now();
+        let fast_done = Rc::new(Cell::new(false));
+        let done = fast_done.clone();
+        Arbiter::spawn(
+            lua_addr
+                .send(LuaMessage::from("slow"))
+                .map(move |res| {
+                    assert_eq!(res, LuaMessage::from("slept"));
+                    assert!(start.elapsed() >= Duration::from_millis(200));
+                    // the other message was handled while sleeping
+                    assert!(done.get());
+                    System::current().stop();
+                })
+                .map_err(|e| println!("actor dead {}", e)),
+        );
+        Arbiter::spawn(
+            lua_addr
+                .send(LuaMessage::from("fast"))
+                .map(move |res| {
+                    assert_eq!(res, LuaMessage::from("fast"));
+                    fast_done.set(true);
+                })
+                .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_huge_duration() {
+        let system = System::new("test");
+
+        let lua_addr = lua_actor_with_handle(
+            r#"
+            if ctx.msg == "sleep" then
+                ctx.sleep(1e30)
+            elseif ctx.msg == "send" then
+                ctx.send(ctx.self, "noop", { timeout = 1e30 })
+            elseif ctx.msg ~= "noop" then
+                ctx[ctx.msg]("noop", 1e30)
+            end
+            "#,
+        )
+        .start();
+
+        let calls = ["sleep", "run_later", "run_interval", "notify_later", "send"]
+            .iter()
+            .map(|f| lua_addr.send(LuaCall(LuaMessage::from(*f))))
+            .collect::<Vec<_>>();
+        Arbiter::spawn(
+            future::join_all(calls)
+                .map(|results| {
+                    for res in results {
+                        match res {
+                            Err(LuaActorError::Conversion(msg)) => assert!(msg.contains("seconds")),
+                            res => panic!("should return conversion error: {:?}", res),
+                        }
+                    }
+                    System::current().stop();
+                })
+                .map_err(|e| println!("actor dead {}", e)),
         );
 
         system.run();
     }
 
     #[test]
-    fn lua_actor_syntax_error() {
-        let res = LuaActorBuilder::new()
-            .on_handle_with_lua(r"return 1+")
-            .build();
+    fn lua_actor_timers() {
+        let system = System::new("test");
+
+        let lua_addr = lua_actor_with_handle(
+            r#"
+            if ctx.msg == "start" then
+                ctx.state.ticks = 0
+                ctx.state.interval = ctx.run_interval("tick", 0.05)
+                ctx.state.later = ctx.run_later("noop", 0.01)
+                ctx.cancel(ctx.run_later("cancelled", 0.1))
+                ctx.run_later("stop", 0.22)
+            elseif ctx.msg == "tick" then
+                ctx.state.ticks = ctx.state.ticks + 1
+            elseif ctx.msg == "cancelled" then
+                ctx.state.cancelled_fired = true
+            elseif ctx.msg == "stop" then
+                ctx.state.interval_cancelled = ctx.cancel(ctx.state.interval)
+                ctx.state.later_cancelled = ctx.cancel(ctx.state.later)
+            elseif ctx.msg == "get" then
+                return ctx.state
+            end
+            "#,
+        )
+        .start();
+
+        lua_addr.do_send(LuaMessage::from("start"));
+        let delay = Delay::new(Duration::from_millis(400)).map(move |()| {
+            Arbiter::spawn(
+                lua_addr
+                    .send(LuaMessage::from("get"))
+                    .map(|res| {
+                        let state = match res {
+                            LuaMessage::Table(t) => t,
+                            res => panic!("unexpected result {:?}", res),
+                        };
+                        // the interval fired 4 times before it was cancelled at 0.22s
+                        match state["ticks"] {
+                            LuaMessage::Integer(n) => assert!((3..=5).contains(&n), "{}", n),
+                            ref n => panic!("unexpected ticks {:?}", n),
+                        }
+                        assert_eq!(state["interval_cancelled"], LuaMessage::from(true));
+                        assert_eq!(state["later_cancelled"], LuaMessage::from(false));
+                        assert!(!state.contains_key("cancelled_fired"));
+                        System::current().stop();
+                    })
+                    .map_err(|e| println!("actor dead {}", e)),
+            );
+        });
+        Arbiter::spawn(delay.map_err(|e| println!("actor dead {}", e)));
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_send() {
+        use std::mem::discriminant;
+        let system = System::new("test");
+
+        struct Callback;
+        impl Actor for Callback {
+            type Context = Context<Self>;
+        }
+
+        impl Handler<LuaMessage> for Callback {
+            type Result = LuaMessage;
+
+            fn handle(&mut self, msg: LuaMessage, _ctx: &mut Context<Self>) -> Self::Result {
+                // check msg type
+                assert_eq!(
+                    discriminant(&msg),
+                    discriminant(&LuaMessage::String("foo".to_string()))
+                );
+                if let LuaMessage::String(s) = msg {
+                    assert_eq!(s, "Hello");
+                    System::current().stop();
+                    LuaMessage::Boolean(true)
+                } else {
+                    unimplemented!()
+                }
+            }
+        }
+        let callback_addr = Callback.start();
+
+        let mut actor = LuaActorBuilder::new()
+            .on_started_with_lua(
+                r#"
+            local result = ctx.send("callback", "Hello")
+            print("result", "=", result)
+            "#,
+            )
+            .build()
+            .unwrap();
+
+        actor.add_recipients("callback", callback_addr.recipient());
+        actor.start();
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_send_to_address() {
+        struct Callback;
+        impl Actor for Callback {
+            type Context = Context<Self>;
+        }
 
-        if let Ok(_) = res {
-            panic!("should return Err(syntax_error)");
+        impl Handler<LuaMessage> for Callback {
+            type Result = LuaMessage;
+
+            fn handle(&mut self, msg: LuaMessage, _ctx: &mut Context<Self>) -> Self::Result {
+                assert_eq!(msg, LuaMessage::from("ping"));
+                LuaMessage::from("pong")
+            }
         }
-    }
 
-    #[should_panic]
-    #[test]
-    fn lua_actor_user_error() {
         let system = System::new("test");
 
         let lua_addr = lua_actor_with_handle(
             r#"
-        print("before")
-        error("foo")
-        print("after")
-        "#,
+            if type(ctx.msg) == "table" then
+                ctx.state.peer = ctx.msg.reply_to
+                return tostring(ctx.state.peer)
+            end
+            ctx.do_send(ctx.state.peer, "ping")
+            return ctx.send(ctx.state.peer, "ping")
+            "#,
         )
         .start();
 
-        let l = lua_addr.send(LuaMessage::from(0));
+        let mut t = HashMap::new();
+        t.insert(
+            "reply_to".to_string(),
+            LuaMessage::from(Callback.start().recipient()),
+        );
+        let l = lua_addr.send(LuaMessage::from(t));
         Arbiter::spawn(
-            l.map(|_| {
-                // it should panic
+            l.and_then(move |res| {
+                assert_eq!(res, LuaMessage::from("recipient"));
+                lua_addr.send(LuaMessage::from("go"))
+            })
+            .map(|res| {
+                assert_eq!(res, LuaMessage::from("pong"));
                 System::current().stop();
             })
             .map_err(|e| println!("actor dead {}", e)),
@@ -399,23 +2718,51 @@ mod tests {
     }
 
     #[test]
-    fn lua_actor_return_table() {
+    fn lua_actor_self() {
+        struct Callback;
+        impl Actor for Callback {
+            type Context = Context<Self>;
+        }
+
+        impl Handler<LuaMessage> for Callback {
+            type Result = LuaMessage;
+
+            fn handle(&mut self, msg: LuaMessage, _ctx: &mut Context<Self>) -> Self::Result {
+                if let LuaMessage::Table(mut t) = msg {
+                    if let Some(LuaMessage::Recipient(r)) = t.remove("reply_to") {
+                        r.do_send(LuaMessage::from("pong")).unwrap();
+                        return LuaMessage::from("ok");
+                    }
+                }
+                unreachable!()
+            }
+        }
+
         let system = System::new("test");
 
-        let lua_addr = lua_actor_with_handle(
+        let callback_addr = Callback.start();
+        let mut actor = lua_actor_with_handle(
             r#"
-        return {x = 1}
-        "#,
-        )
-        .start();
+            if ctx.msg == "start" then
+                return ctx.send("callback", { reply_to = ctx.self })
+            elseif ctx.msg == "pong" then
+                ctx.state.pong = true
+            else
+                return ctx.state.pong
+            end
+            "#,
+        );
+        actor.add_recipients("callback", callback_addr.recipient());
+        let lua_addr = actor.start();
 
-        let l = lua_addr.send(LuaMessage::from(3));
+        let l = lua_addr.send(LuaMessage::from("start"));
         Arbiter::spawn(
-            l.map(|res| {
-                let mut t = HashMap::new();
-                t.insert("x".to_string(), LuaMessage::from(1));
-
-                assert_eq!(res, LuaMessage::from(t));
+            l.and_then(move |res| {
+                assert_eq!(res, LuaMessage::from("ok"));
+                lua_addr.send(LuaMessage::from("check"))
+            })
+            .map(|res| {
+                assert_eq!(res, LuaMessage::from(true));
                 System::current().stop();
             })
             .map_err(|e| println!("actor dead {}", e)),
@@ -425,31 +2772,68 @@ mod tests {
     }
 
     #[test]
-    fn lua_actor_state() {
+    fn lua_actor_recipients() {
+        struct Callback;
+        impl Actor for Callback {
+            type Context = Context<Self>;
+        }
+
+        impl Handler<LuaMessage> for Callback {
+            type Result = LuaMessage;
+
+            fn handle(&mut self, _: LuaMessage, _ctx: &mut Context<Self>) -> Self::Result {
+                LuaMessage::from("pong")
+            }
+        }
+
         let system = System::new("test");
 
         let lua_addr = lua_actor_with_handle(
             r#"
-        if not ctx.state.x then ctx.state.x = 0 end
-
-        ctx.state.x = ctx.state.x + 1
-        return ctx.state.x
-        "#,
+            if type(ctx.msg) == "table" then
+                ctx.register("peer", ctx.msg.peer)
+                ctx.register("alias", "peer")
+                return ctx.recipients()
+            end
+            if ctx.has_recipient("callback") then
+                return ctx.send("callback", "ping")
+            end
+            return false
+            "#,
         )
         .start();
+        let callback = Callback.start().recipient();
 
-        let l = lua_addr.send(LuaMessage::Nil);
+        let mut t = HashMap::new();
+        t.insert("peer".to_string(), LuaMessage::from(callback.clone()));
+        let l = lua_addr.send(LuaMessage::from("go"));
         Arbiter::spawn(
-            l.map(move |res| {
-                assert_eq!(res, LuaMessage::from(1));
-                let l2 = lua_addr.send(LuaMessage::Nil);
-                Arbiter::spawn(
-                    l2.map(|res| {
-                        assert_eq!(res, LuaMessage::from(2));
-                        System::current().stop();
+            l.and_then(move |res| {
+                assert_eq!(res, LuaMessage::from(false));
+                lua_addr
+                    .send(AddRecipient {
+                        name: "callback".to_string(),
+                        recipient: callback,
+                    })
+                    .and_then(move |prev| {
+                        assert!(prev.is_none());
+                        lua_addr.send(LuaMessage::from("go")).map(|res| {
+                            assert_eq!(res, LuaMessage::from("pong"));
+                            lua_addr
+                        })
+                    })
+            })
+            .and_then(move |lua_addr| {
+                lua_addr
+                    .send(RemoveRecipient("callback".to_string()))
+                    .and_then(move |removed| {
+                        assert!(removed.is_some());
+                        lua_addr.send(LuaMessage::from(t))
                     })
-                    .map_err(|e| println!("actor dead {}", e)),
-                );
+            })
+            .map(|res| {
+                assert_eq!(res, LuaMessage::from(vec!["alias", "peer"]));
+                System::current().stop();
             })
             .map_err(|e| println!("actor dead {}", e)),
         );
@@ -458,86 +2842,77 @@ mod tests {
     }
 
     #[test]
-    fn lua_actor_notify() {
+    fn lua_actor_registry() {
         let system = System::new("test");
 
-        let addr = LuaActorBuilder::new()
-            .on_started_with_lua(
-                r#"
-            ctx.notify(100)
-            "#,
-            )
+        LuaActorBuilder::new()
             .on_handle_with_lua(
                 r#"
-            if ctx.msg == 100 then
-                ctx.state.notified = ctx.msg
-            end
-
-            return ctx.msg + ctx.state.notified
-            "#,
+                if ctx.msg == "die" then
+                    ctx.terminate()
+                end
+                return ctx.msg .. "!"
+                "#,
             )
+            .register_as("echo")
             .build()
             .unwrap()
             .start();
-
-        let delay = Delay::new(Duration::from_secs(1)).map(move |()| {
-            let l = addr.send(LuaMessage::from(1));
-            Arbiter::spawn(
-                l.map(|res| {
-                    assert_eq!(res, LuaMessage::from(101));
-                    System::current().stop();
-                })
-                .map_err(|e| println!("actor dead {}", e)),
-            )
-        });
-        Arbiter::spawn(delay.map_err(|e| println!("actor dead {}", e)));
-
-        system.run();
-    }
-
-    #[test]
-    fn lua_actor_notify_later() {
-        let system = System::new("test");
-
-        let addr = LuaActorBuilder::new()
-            .on_started_with_lua(
-                r#"
-            ctx.notify_later(100, 1)
-            "#,
-            )
-            .on_handle_with_lua(
-                r#"
-            if ctx.msg == 100 then
-                ctx.state.notified = ctx.msg
+        let client = lua_actor_with_handle(
+            r#"
+            if ctx.msg == "register" then
+                ctx.register_global("client")
+            elseif ctx.msg == "die" then
+                ctx.do_send("echo", "die")
+            else
+                local res, err = ctx.send(ctx.msg, "hi")
+                return err or res
             end
-
-            return ctx.msg + ctx.state.notified
             "#,
-            )
-            .build()
-            .unwrap()
-            .start();
+        )
+        .start();
 
-        let delay = Delay::new(Duration::from_secs(2)).map(move |()| {
-            let l2 = addr.send(LuaMessage::from(1));
-            Arbiter::spawn(
-                l2.map(|res| {
-                    assert_eq!(res, LuaMessage::from(101));
-                    System::current().stop();
-                })
-                .map_err(|e| println!("actor dead {}", e)),
-            )
-        });
-        Arbiter::spawn(delay.map_err(|e| println!("actor dead {}", e)));
+        let names = |found: Vec<(String, Recipient<LuaMessage>)>| {
+            found.into_iter().map(|(name, _)| name).collect::<Vec<_>>()
+        };
+        let l = client.send(LuaMessage::from("nobody"));
+        Arbiter::spawn(
+            l.and_then(move |res| {
+                assert_eq!(res, LuaMessage::from("recipient not found: nobody"));
+                // names are not patterns
+                client
+                    .send(LuaMessage::from("ech*"))
+                    .map(move |res| (client, res))
+            })
+            .and_then(|(client, res)| {
+                assert_eq!(res, LuaMessage::from("recipient not found: ech*"));
+                client
+                    .send(LuaMessage::from("echo"))
+                    .map(move |res| (client, res))
+            })
+            .and_then(|(client, res)| {
+                assert_eq!(res, LuaMessage::from("hi!"));
+                client.do_send(LuaMessage::from("register"));
+                client.send(LuaMessage::from("die"))
+            })
+            .and_then(|_| {
+                Delay::new(Duration::from_millis(200))
+                    .map_err(|_| MailboxError::Timeout)
+                    .and_then(|_| LuaRegistry::from_registry().send(Lookup("*".to_string())))
+            })
+            .map(move |found| {
+                // `echo` is unregistered when it stops
+                assert_eq!(names(found), vec!["client"]);
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
 
         system.run();
     }
 
     #[test]
-    fn lua_actor_send() {
-        use std::mem::discriminant;
-        let system = System::new("test");
-
+    fn lua_actor_spawn() {
         struct Callback;
         impl Actor for Callback {
             type Context = Context<Self>;
@@ -547,40 +2922,160 @@ mod tests {
             type Result = LuaMessage;
 
             fn handle(&mut self, msg: LuaMessage, _ctx: &mut Context<Self>) -> Self::Result {
-                // check msg type
-                assert_eq!(
-                    discriminant(&msg),
-                    discriminant(&LuaMessage::String("foo".to_string()))
-                );
-                if let LuaMessage::String(s) = msg {
-                    assert_eq!(s, "Hello");
-                    System::current().stop();
-                    LuaMessage::Boolean(true)
-                } else {
-                    unimplemented!()
-                }
+                assert_eq!(msg, LuaMessage::from("child stopped"));
+                System::current().stop();
+                LuaMessage::Nil
             }
         }
-        let callback_addr = Callback.start();
+
+        let system = System::new("test");
 
         let mut actor = LuaActorBuilder::new()
-            .on_started_with_lua(
+            .spawn_dir("./src/lua/test")
+            .on_handle_with_lua(
                 r#"
-            local result = ctx.send("callback", "Hello")
-            print("result", "=", result)
+            if ctx.msg == "start" then
+                ctx.state.worker = ctx.spawn({
+                    handle = [[
+                        if ctx.msg == "die" then
+                            ctx.terminate()
+                        end
+                        return ctx.msg * 2
+                    ]]
+                })
+                ctx.state.file_worker = ctx.spawn_file("test.lua")
+                return ctx.send(ctx.state.worker, 21) + ctx.send(ctx.state.file_worker, 1)
+            elseif ctx.msg == "stop_worker" then
+                ctx.do_send(ctx.state.worker, "die")
+            elseif type(ctx.msg) == "table" and ctx.msg.type == "child_stopped" then
+                assert(ctx.msg.child == ctx.state.worker)
+                ctx.do_send("callback", "child stopped")
+            end
             "#,
             )
             .build()
             .unwrap();
+        actor.add_recipients("callback", Callback.start().recipient());
+        let lua_addr = actor.start();
+
+        let l = lua_addr.send(LuaMessage::from("start"));
+        Arbiter::spawn(
+            l.map(move |res| {
+                assert_eq!(res, LuaMessage::from(42 + 421));
+                lua_addr.do_send(LuaMessage::from("stop_worker"));
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_spawn_limits() {
+        let system = System::new("test");
+
+        let script = r#"
+            local function try(f, ...)
+                local ok, err = pcall(f, ...)
+                return ok and "ok" or tostring(err)
+            end
+            if ctx.msg == 1 then
+                return {
+                    try(ctx.spawn_file, "test.lua"),
+                    try(ctx.spawn_file, "../../actor.rs"),
+                    try(ctx.spawn, { started = "ctx.spawn({})" }),
+                }
+            end
+            return { try(ctx.spawn, {}) }
+            "#;
+        let start =
+            |builder: LuaActorBuilder| builder.on_handle_with_lua(script).build().unwrap().start();
+        let results = |res: Result<LuaMessage, LuaActorError>| match res.unwrap() {
+            LuaMessage::Array(items) => items
+                .into_iter()
+                .map(|item| match item {
+                    LuaMessage::String(s) => s,
+                    _ => panic!("should be a string"),
+                })
+                .collect::<Vec<_>>(),
+            _ => panic!("should be an array"),
+        };
+
+        let disabled = start(LuaActorBuilder::new()).send(LuaCall(LuaMessage::from(1)));
+        let sandboxed = start(
+            LuaActorBuilder::new()
+                .spawn_dir("./src/lua/test")
+                .sandbox(SandboxProfile::no_io()),
+        )
+        .send(LuaCall(LuaMessage::from(1)));
+        let limited = start(
+            LuaActorBuilder::new()
+                .spawn_dir("./src/lua/test")
+                .max_children(3),
+        );
+        let l = disabled
+            .join3(sandboxed, limited.send(LuaCall(LuaMessage::from(1))))
+            .and_then(move |(disabled, sandboxed, res)| {
+                assert!(results(disabled)[0].contains("ctx.spawn_file is disabled"));
+                assert!(results(sandboxed)[0].contains("ctx.spawn_file is disabled"));
+
+                let res = results(res);
+                assert_eq!(res[0], "ok");
+                assert!(res[1].contains("outside of the spawn directory"));
+                assert_eq!(res[2], "ok");
+                // wait for the child to spawn its own child
+                Delay::new(Duration::from_millis(100))
+                    .map_err(|_| MailboxError::Closed)
+                    .and_then(move |_| limited.send(LuaCall(LuaMessage::from(2))))
+            })
+            .map(move |res| {
+                // the children of children count toward the limit
+                assert!(results(res)[0].contains("more than 3 children"));
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e));
+        Arbiter::spawn(l);
+
+        system.run();
+    }
+
+    #[test]
+    fn lua_actor_spawn_stop_children() {
+        let system = System::new("test");
+
+        let lua_addr = lua_actor_with_handle(
+            r#"
+            if ctx.msg == "start" then
+                return ctx.spawn({ handle = "return ctx.msg" })
+            end
+            ctx.terminate()
+            "#,
+        )
+        .start();
+
+        let l = lua_addr.send(LuaMessage::from("start"));
+        Arbiter::spawn(
+            l.and_then(move |child| {
+                lua_addr.do_send(LuaMessage::from("stop"));
+                Delay::new(Duration::from_millis(200))
+                    .map_err(|_| MailboxError::Timeout)
+                    .and_then(move |_| match child {
+                        LuaMessage::Recipient(child) => child.send(LuaMessage::from(1)),
+                        _ => panic!("expected the address of the child"),
+                    })
+            })
+            .then(|res| {
+                assert!(matches!(res, Err(MailboxError::Closed)));
+                System::current().stop();
+                Ok::<(), ()>(())
+            }),
+        );
 
-        actor.add_recipients("callback", callback_addr.recipient());
-        actor.start();
         system.run();
     }
 
     #[test]
     fn lua_actor_thread_yield() {
-        use std::mem::discriminant;
         struct Callback;
         impl Actor for Callback {
             type Context = Context<Self>;
@@ -590,7 +3085,7 @@ mod tests {
             type Result = LuaMessage;
 
             fn handle(&mut self, _: LuaMessage, _ctx: &mut Context<Self>) -> Self::Result {
-                LuaMessage::Nil
+                LuaMessage::from("World")
             }
         }
 
@@ -614,10 +3109,8 @@ mod tests {
         let l = addr.send(LuaMessage::Nil);
         Arbiter::spawn(
             l.map(move |res| {
-                assert_eq!(
-                    discriminant(&res),
-                    discriminant(&LuaMessage::ThreadYield("foo".to_string()))
-                );
+                // the reply is deferred until the suspended coroutine returns
+                assert_eq!(res, LuaMessage::from("World"));
                 System::current().stop();
             })
             .map_err(|e| println!("actor dead {}", e)),
@@ -669,8 +3162,7 @@ mod tests {
                 );
                 if let LuaMessage::String(s) = msg {
                     assert_eq!(s, "Hello from callback");
-                    System::current().stop();
-                    LuaMessage::Nil
+                    LuaMessage::from("checked")
                 } else {
                     unimplemented!()
                 }
@@ -683,7 +3175,7 @@ mod tests {
                 r#"
             local result = ctx.send("callback", ctx.msg)
             print("send result", "=", result)
-            ctx.send("check", result)
+            return ctx.send("check", result)
             "#,
             )
             .build()
@@ -697,10 +3189,8 @@ mod tests {
         let l = addr.send(LuaMessage::String("Hello".to_string()));
         Arbiter::spawn(
             l.map(move |res| {
-                assert_eq!(
-                    discriminant(&res),
-                    discriminant(&LuaMessage::ThreadYield("foo".to_string()))
-                );
+                assert_eq!(res, LuaMessage::from("checked"));
+                System::current().stop();
             })
             .map_err(|e| println!("actor dead {}", e)),
         );
@@ -1,23 +1,71 @@
-use std::fs::File;
-use std::io::prelude::*;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
 use crate::actor::LuaActor;
-use rlua::{Error as LuaError, Lua};
+use crate::error::LuaActorError;
+use crate::limits::Limits;
+use crate::message::{LuaMessage, Utf8Policy};
+use crate::persist::{PersistPolicy, Persistence};
+use crate::sandbox::SandboxProfile;
+use crate::watcher::{read_to_string, ScriptWatcher};
+use rlua::Lua;
+
+// a script given as source code, or as a file read when the actor is built
+#[derive(Clone)]
+enum Script {
+    Lua(String),
+    File(String),
+}
 
 /// `LuaActorBuilder` creates a new `LuaActor` with given Lua script.
+#[derive(Clone)]
 pub struct LuaActorBuilder {
-    started: Option<String>,
-    handle: Option<String>,
-    stopped: Option<String>,
+    started: Option<Script>,
+    handle: Option<Script>,
+    stopped: Option<Script>,
+    watch: bool,
+    watch_interval: Duration,
+    max_instructions: Option<u32>,
+    handler_timeout: Option<Duration>,
+    memory_limit: Option<usize>,
+    sandbox: SandboxProfile,
+    initial_state: Option<LuaMessage>,
+    persist: Option<(PathBuf, PersistPolicy)>,
+    utf8_policy: Utf8Policy,
+    names: Vec<String>,
+    max_suspended: Option<usize>,
+    max_suspended_age: Option<Duration>,
+    spawn_dir: Option<PathBuf>,
+    max_children: Option<usize>,
+    // running actors created with `ctx.spawn` by the root actor and its descendants
+    descendants: Option<Arc<AtomicUsize>>,
 }
 
 impl Default for LuaActorBuilder {
     fn default() -> LuaActorBuilder {
-        let noop = Some("return".to_string());
+        let noop = Some(Script::Lua("return".to_string()));
         LuaActorBuilder {
             started: noop.clone(),
             handle: noop.clone(),
             stopped: noop.clone(),
+            watch: false,
+            watch_interval: Duration::from_secs(1),
+            max_instructions: None,
+            handler_timeout: None,
+            memory_limit: None,
+            sandbox: SandboxProfile::full(),
+            initial_state: None,
+            persist: None,
+            utf8_policy: Utf8Policy::default(),
+            names: vec![],
+            max_suspended: None,
+            max_suspended_age: None,
+            spawn_dir: None,
+            max_children: None,
+            descendants: None,
         }
     }
 }
@@ -30,68 +78,286 @@ impl LuaActorBuilder {
 
     /// create a `started` hook with given lua file
     pub fn on_started(mut self, filename: &str) -> Self {
-        self.started = Some(read_to_string(filename));
+        self.started = Some(Script::File(filename.to_string()));
         self
     }
 
     /// create a `started` hook with given lua script
     pub fn on_started_with_lua(mut self, script: &str) -> Self {
-        self.started = Some(script.to_string());
+        self.started = Some(Script::Lua(script.to_string()));
         self
     }
 
     /// handle message with given lua file
+    ///
+    /// Files are read when the actor is built.
     pub fn on_handle(mut self, filename: &str) -> Self {
-        self.handle = Some(read_to_string(filename));
+        self.handle = Some(Script::File(filename.to_string()));
         self
     }
 
     /// handle message with given lua script
     pub fn on_handle_with_lua(mut self, script: &str) -> Self {
-        self.handle = Some(script.to_string());
+        self.handle = Some(Script::Lua(script.to_string()));
         self
     }
 
     /// create a `stopped` hook with given lua file.
     pub fn on_stopped(mut self, filename: &str) -> Self {
-        self.stopped = Some(read_to_string(filename));
+        self.stopped = Some(Script::File(filename.to_string()));
         self
     }
 
     /// create a `stopped` hook with given lua script
     pub fn on_stopped_with_lua(mut self, script: &str) -> Self {
-        self.stopped = Some(script.to_string());
+        self.stopped = Some(Script::Lua(script.to_string()));
+        self
+    }
+
+    /// reload the scripts given as files when they are changed.
+    ///
+    /// Files are polled every `watch_interval`. A script failed to compile is logged to stderr,
+    /// the actor keeps running the last version compiled successfully.
+    pub fn watch(mut self, enabled: bool) -> Self {
+        self.watch = enabled;
+        self
+    }
+
+    /// set the interval to poll the script files with `watch`. Defaults to 1 second.
+    pub fn watch_interval(mut self, interval: Duration) -> Self {
+        self.watch_interval = interval;
+        self
+    }
+
+    /// abort a handler with `LuaActorError::InstructionLimit` after it executed `count` lua instructions.
+    ///
+    /// The budget is reset every time a handler starts or a suspended coroutine resumes.
+    /// `pcall` can't catch the error to keep the handler running.
+    pub fn max_instructions_per_message(mut self, count: u32) -> Self {
+        self.max_instructions = Some(count);
+        self
+    }
+
+    /// abort a handler with `LuaActorError::Timeout` after it ran longer than `timeout`.
+    ///
+    /// The time spent waiting in `ctx.send` is not counted.
+    pub fn handler_timeout(mut self, timeout: Duration) -> Self {
+        self.handler_timeout = Some(timeout);
+        self
+    }
+
+    /// limit the number of coroutines suspended in `ctx.send`, `ctx.gather`, `ctx.race` or `ctx.sleep`.
+    ///
+    /// When the limit is exceeded, the oldest coroutines are dropped and the messages they were
+    /// handling fail with `LuaActorError::Evicted`.
+    pub fn max_suspended_threads(mut self, count: usize) -> Self {
+        self.max_suspended = Some(count);
+        self
+    }
+
+    /// drop the coroutines suspended for longer than `age`, like `max_suspended_threads`.
+    ///
+    /// The age of the coroutines is checked every `age / 2`.
+    pub fn max_suspended_age(mut self, age: Duration) -> Self {
+        self.max_suspended_age = Some(age);
+        self
+    }
+
+    /// limit the memory used by the lua VM to `bytes`.
+    ///
+    /// A handler which allocates over the limit fails with `LuaActorError::OutOfMemory`.
+    pub fn memory_limit(mut self, bytes: usize) -> Self {
+        self.memory_limit = Some(bytes);
+        self
+    }
+
+    /// allow `ctx.spawn_file` to create children from the lua files in `dir`.
+    ///
+    /// `ctx.spawn_file` is disabled without it, or if the sandbox profile is not `SandboxProfile::full()`.
+    /// Files outside of `dir` can't be spawned.
+    pub fn spawn_dir(mut self, dir: &str) -> Self {
+        self.spawn_dir = Some(PathBuf::from(dir));
+        self
+    }
+
+    /// limit the number of running actors created with `ctx.spawn` and `ctx.spawn_file`.
+    ///
+    /// The limit is shared by the actor and all its descendants.
+    /// Spawning over the limit fails with `LuaActorError::ChildLimit`.
+    pub fn max_children(mut self, count: usize) -> Self {
+        self.max_children = Some(count);
+        self
+    }
+
+    /// restrict the standard library available to scripts. Defaults to `SandboxProfile::full()`.
+    pub fn sandbox(mut self, profile: SandboxProfile) -> Self {
+        self.sandbox = profile;
+        self
+    }
+
+    /// set the initial `ctx.state`. `state` must be a `LuaMessage::Table` or `LuaMessage::Array`.
+    pub fn initial_state(mut self, state: LuaMessage) -> Self {
+        self.initial_state = Some(state);
+        self
+    }
+
+    /// write `ctx.state` to the file at `path` according to `policy`.
+    ///
+    /// If the file exists when the actor is built, the state written previously is restored
+    /// and `initial_state` is ignored. Addresses kept in `ctx.state` are restored as `nil`.
+    pub fn persist_state(mut self, path: &str, policy: PersistPolicy) -> Self {
+        self.persist = Some((PathBuf::from(path), policy));
+        self
+    }
+
+    /// set how Lua strings which are not valid UTF-8 are converted. Defaults to `Utf8Policy::Bytes`.
+    pub fn utf8_policy(mut self, policy: Utf8Policy) -> Self {
+        self.utf8_policy = policy;
+        self
+    }
+
+    /// register the actor as `name` in `LuaRegistry` when it starts. Can be called multiple times.
+    ///
+    /// The actor is unregistered when it stops.
+    pub fn register_as(mut self, name: &str) -> Self {
+        self.names.push(name.to_string());
         self
     }
 
     /// build the actor with a preconfigured lua VM
     ///
     /// It's important to use the `rlua` interface exported by `actix-lua` with `use actix_lua::dev::rlua::*`
-    pub fn build_with_vm(self, vm: Lua) -> Result<LuaActor, LuaError> {
-        LuaActor::new_with_vm(
+    ///
+    /// Setting a instruction budget or a timeout replaces the hook of the VM.
+    /// The sandbox profile removes libraries and functions it doesn't allow from the VM.
+    pub fn build_with_vm(self, vm: Lua) -> Result<LuaActor, LuaActorError> {
+        vm.context(|ctx| self.sandbox.apply(ctx))?;
+        let mut actor = LuaActor::new_with_vm(
             vm,
-            self.started.clone(),
-            self.handle.clone(),
-            self.stopped.clone(),
-        )
+            source(&self.started)?,
+            source(&self.handle)?,
+            source(&self.stopped)?,
+        )?;
+        self.configure(&mut actor)?;
+        Ok(actor)
     }
 
     /// build the actor
-    pub fn build(self) -> Result<LuaActor, LuaError> {
-        LuaActor::new(
-            self.started.clone(),
-            self.handle.clone(),
-            self.stopped.clone(),
-        )
+    pub fn build(self) -> Result<LuaActor, LuaActorError> {
+        let mut actor = LuaActor::new_with_vm(
+            self.sandbox.create_vm()?,
+            source(&self.started)?,
+            source(&self.handle)?,
+            source(&self.stopped)?,
+        )?;
+        self.configure(&mut actor)?;
+        Ok(actor)
+    }
+
+    fn configure(&self, actor: &mut LuaActor) -> Result<(), LuaActorError> {
+        actor.set_utf8_policy(self.utf8_policy)?;
+        actor.set_spawner(self.child());
+        actor.set_names(self.names.clone());
+        actor.set_suspend_limits(self.max_suspended, self.max_suspended_age);
+
+        actor.set_limits(Limits::new(
+            self.max_instructions,
+            self.handler_timeout,
+            self.memory_limit,
+        ))?;
+
+        if self.watch {
+            let mut watcher = ScriptWatcher::new(self.watch_interval);
+            for (name, script) in &[
+                ("started", &self.started),
+                ("handle", &self.handle),
+                ("stopped", &self.stopped),
+            ] {
+                if let Some(Script::File(path)) = script {
+                    watcher.watch(name, path);
+                }
+            }
+            actor.set_watcher(watcher);
+        }
+
+        let mut state = self.initial_state.clone();
+        if let Some((ref path, policy)) = self.persist {
+            let persistence = Persistence::new(path.clone(), policy);
+            if let Some(persisted) = persistence.load()? {
+                state = Some(persisted);
+            }
+            actor.set_persistence(persistence);
+        }
+        if let Some(state) = state {
+            actor.restore_state(state)?;
+        }
+        Ok(())
     }
 }
 
-fn read_to_string(filename: &str) -> String {
-    let mut f = File::open(filename).expect("File not found");
-    let mut body = String::new();
-    f.read_to_string(&mut body).expect("Failed to read file");
+impl LuaActorBuilder {
+    // children created with `ctx.spawn` share the sandbox and the limits of their parent
+    fn child(&self) -> LuaActorBuilder {
+        LuaActorBuilder {
+            max_instructions: self.max_instructions,
+            handler_timeout: self.handler_timeout,
+            memory_limit: self.memory_limit,
+            sandbox: self.sandbox.clone(),
+            utf8_policy: self.utf8_policy,
+            max_suspended: self.max_suspended,
+            max_suspended_age: self.max_suspended_age,
+            spawn_dir: self.spawn_dir.clone(),
+            max_children: self.max_children,
+            descendants: Some(self.descendants.clone().unwrap_or_default()),
+            ..LuaActorBuilder::default()
+        }
+    }
 
-    body
+    // the builder of `ctx.spawn_file(filename)`
+    pub(crate) fn with_file(self, filename: &str) -> Result<LuaActorBuilder, LuaActorError> {
+        let dir = match self.spawn_dir {
+            Some(ref dir) if self.sandbox.is_full() => dir.canonicalize()?,
+            _ => {
+                return Err(permission_denied(
+                    "ctx.spawn_file is disabled, see LuaActorBuilder::spawn_dir".to_string(),
+                ))
+            }
+        };
+        let path = dir.join(filename).canonicalize()?;
+        if !path.starts_with(&dir) {
+            return Err(permission_denied(format!(
+                "{} is outside of the spawn directory",
+                filename
+            )));
+        }
+        Ok(self.on_handle(&path.to_string_lossy()))
+    }
+
+    // build a child counted in the running descendants of the root actor
+    pub(crate) fn build_child(self) -> Result<LuaActor, LuaActorError> {
+        let descendants = self.descendants.clone().unwrap_or_default();
+        if let Some(max) = self.max_children {
+            if descendants.load(Ordering::SeqCst) >= max {
+                return Err(LuaActorError::ChildLimit(max));
+            }
+        }
+        let mut actor = self.build()?;
+        descendants.fetch_add(1, Ordering::SeqCst);
+        actor.set_spawned(descendants);
+        Ok(actor)
+    }
+}
+
+fn permission_denied(msg: String) -> LuaActorError {
+    LuaActorError::Io(io::Error::new(io::ErrorKind::PermissionDenied, msg))
+}
+
+fn source(script: &Option<Script>) -> Result<Option<String>, LuaActorError> {
+    match script {
+        Some(Script::Lua(s)) => Ok(Some(s.clone())),
+        Some(Script::File(path)) => Ok(Some(read_to_string(path)?)),
+        None => Ok(None),
+    }
 }
 
 #[cfg(test)]
@@ -107,7 +373,7 @@ mod tests {
 
         if let Err(e) = res {
             assert_eq!(
-                discriminant(&LuaError::RuntimeError("unexpected symbol".to_string())),
+                discriminant(&LuaActorError::Compile("unexpected symbol".to_string())),
                 discriminant(&e)
             );
         // ok
@@ -116,4 +382,19 @@ mod tests {
         }
     }
 
+    #[test]
+    fn build_file_not_found() {
+        let res = LuaActorBuilder::new()
+            .on_handle("./src/lua/test/not_found.lua")
+            .build();
+
+        if let Err(e) = res {
+            assert_eq!(
+                discriminant(&LuaActorError::Io(std::io::ErrorKind::NotFound.into())),
+                discriminant(&e)
+            );
+        } else {
+            panic!("should return error");
+        }
+    }
 }
@@ -0,0 +1,484 @@
+use ::actix::Recipient;
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::mem;
+
+use crate::error::LuaActorError;
+use crate::message::LuaMessage;
+
+/// Convert a value to `LuaMessage`.
+///
+/// Implemented for primitive types, `String`, `Option`, `Vec`, `HashMap<String, T>`, `Recipient<LuaMessage>`
+/// and types with `#[derive(IntoLuaMessage)]`.
+pub trait IntoLuaMessage {
+    fn into_lua_message(self) -> LuaMessage;
+}
+
+/// Convert a `LuaMessage` to a value.
+///
+/// Implemented for primitive types, `String`, `Option`, `Vec`, `HashMap<String, T>`, `Recipient<LuaMessage>`
+/// and types with `#[derive(FromLuaMessage)]`.
+/// Errors in nested values are returned as `LuaActorError::FieldConversion` with the path of the value.
+pub trait FromLuaMessage: Sized {
+    fn from_lua_message(msg: LuaMessage) -> Result<Self, LuaActorError>;
+}
+
+impl IntoLuaMessage for LuaMessage {
+    fn into_lua_message(self) -> LuaMessage {
+        self
+    }
+}
+
+impl FromLuaMessage for LuaMessage {
+    fn from_lua_message(msg: LuaMessage) -> Result<LuaMessage, LuaActorError> {
+        Ok(msg)
+    }
+}
+
+impl IntoLuaMessage for Recipient<LuaMessage> {
+    fn into_lua_message(self) -> LuaMessage {
+        LuaMessage::Recipient(self)
+    }
+}
+
+impl FromLuaMessage for Recipient<LuaMessage> {
+    fn from_lua_message(msg: LuaMessage) -> Result<Recipient<LuaMessage>, LuaActorError> {
+        match msg {
+            LuaMessage::Recipient(r) => Ok(r),
+            msg => Err(expected("recipient", &msg)),
+        }
+    }
+}
+
+impl IntoLuaMessage for bool {
+    fn into_lua_message(self) -> LuaMessage {
+        LuaMessage::Boolean(self)
+    }
+}
+
+impl FromLuaMessage for bool {
+    fn from_lua_message(msg: LuaMessage) -> Result<bool, LuaActorError> {
+        match msg {
+            LuaMessage::Boolean(b) => Ok(b),
+            msg => Err(expected("boolean", &msg)),
+        }
+    }
+}
+
+impl IntoLuaMessage for String {
+    fn into_lua_message(self) -> LuaMessage {
+        LuaMessage::String(self)
+    }
+}
+
+impl IntoLuaMessage for &str {
+    fn into_lua_message(self) -> LuaMessage {
+        LuaMessage::String(self.to_string())
+    }
+}
+
+impl FromLuaMessage for String {
+    fn from_lua_message(msg: LuaMessage) -> Result<String, LuaActorError> {
+        match msg {
+            LuaMessage::String(s) => Ok(s),
+            msg => Err(expected("string", &msg)),
+        }
+    }
+}
+
+macro_rules! convert_integer {
+    ($($x:ty),*) => {
+        $(
+        impl IntoLuaMessage for $x {
+            fn into_lua_message(self) -> LuaMessage {
+                // integers out of the range of lua integers become floats instead of wrapping
+                match i64::try_from(self) {
+                    Ok(n) => LuaMessage::Integer(n),
+                    Err(_) => LuaMessage::Number(self as f64),
+                }
+            }
+        }
+
+        impl FromLuaMessage for $x {
+            fn from_lua_message(msg: LuaMessage) -> Result<$x, LuaActorError> {
+                let n = match msg {
+                    LuaMessage::Integer(n) => n,
+                    // lua arithmetic may produce floats with integral values, e.g. `10 / 2`
+                    LuaMessage::Number(n) if n.fract() == 0.0 => n as i64,
+                    msg => return Err(expected("integer", &msg)),
+                };
+                <$x>::try_from(n).map_err(|_| {
+                    LuaActorError::Conversion(format!(
+                        "{} is out of range for {}",
+                        n,
+                        stringify!($x)
+                    ))
+                })
+            }
+        }
+        )*
+    };
+}
+
+convert_integer!(i8, u8, i16, u16, i32, u32, i64, u64, isize, usize);
+
+macro_rules! convert_float {
+    ($($x:ty),*) => {
+        $(
+        impl IntoLuaMessage for $x {
+            fn into_lua_message(self) -> LuaMessage {
+                LuaMessage::Number(f64::from(self))
+            }
+        }
+
+        impl FromLuaMessage for $x {
+            fn from_lua_message(msg: LuaMessage) -> Result<$x, LuaActorError> {
+                match msg {
+                    LuaMessage::Number(n) => Ok(n as $x),
+                    LuaMessage::Integer(n) => Ok(n as $x),
+                    msg => Err(expected("number", &msg)),
+                }
+            }
+        }
+        )*
+    };
+}
+
+convert_float!(f32, f64);
+
+impl<T: IntoLuaMessage> IntoLuaMessage for Option<T> {
+    fn into_lua_message(self) -> LuaMessage {
+        match self {
+            Some(v) => v.into_lua_message(),
+            None => LuaMessage::Nil,
+        }
+    }
+}
+
+impl<T: FromLuaMessage> FromLuaMessage for Option<T> {
+    fn from_lua_message(msg: LuaMessage) -> Result<Option<T>, LuaActorError> {
+        match msg {
+            LuaMessage::Nil => Ok(None),
+            msg => T::from_lua_message(msg).map(Some),
+        }
+    }
+}
+
+impl<T: IntoLuaMessage> IntoLuaMessage for Box<T> {
+    fn into_lua_message(self) -> LuaMessage {
+        (*self).into_lua_message()
+    }
+}
+
+impl<T: FromLuaMessage> FromLuaMessage for Box<T> {
+    fn from_lua_message(msg: LuaMessage) -> Result<Box<T>, LuaActorError> {
+        T::from_lua_message(msg).map(Box::new)
+    }
+}
+
+impl<T: IntoLuaMessage> IntoLuaMessage for Vec<T> {
+    fn into_lua_message(self) -> LuaMessage {
+        LuaMessage::Array(self.into_iter().map(T::into_lua_message).collect())
+    }
+}
+
+impl<T: FromLuaMessage> FromLuaMessage for Vec<T> {
+    fn from_lua_message(msg: LuaMessage) -> Result<Vec<T>, LuaActorError> {
+        let mut items = match msg {
+            LuaMessage::Array(items) => items,
+            // the empty lua table is an empty `Table`
+            LuaMessage::Table(ref t) if t.is_empty() => vec![],
+            msg => return Err(expected("array", &msg)),
+        };
+        (0..items.len()).map(|i| element(&mut items, i)).collect()
+    }
+}
+
+impl<T: IntoLuaMessage> IntoLuaMessage for HashMap<String, T> {
+    fn into_lua_message(self) -> LuaMessage {
+        LuaMessage::Table(
+            self.into_iter()
+                .map(|(k, v)| (k, v.into_lua_message()))
+                .collect(),
+        )
+    }
+}
+
+impl<T: FromLuaMessage> FromLuaMessage for HashMap<String, T> {
+    fn from_lua_message(msg: LuaMessage) -> Result<HashMap<String, T>, LuaActorError> {
+        table(msg)?
+            .into_iter()
+            .map(|(k, v)| match T::from_lua_message(v) {
+                Ok(v) => Ok((k, v)),
+                Err(e) => Err(in_field(e, &k)),
+            })
+            .collect()
+    }
+}
+
+fn kind(msg: &LuaMessage) -> &'static str {
+    match msg {
+        LuaMessage::String(_) => "string",
+        LuaMessage::Integer(_) => "integer",
+        LuaMessage::Number(_) => "number",
+        LuaMessage::Boolean(_) => "boolean",
+        LuaMessage::Nil => "nil",
+        LuaMessage::Table(_) => "table",
+        LuaMessage::Array(_) => "array",
+        LuaMessage::Bytes(_) => "bytes",
+        LuaMessage::ThreadYield(_) => "thread yield",
+        LuaMessage::Recipient(_) => "recipient",
+    }
+}
+
+fn expected(what: &str, got: &LuaMessage) -> LuaActorError {
+    LuaActorError::Conversion(format!("expected {}, got {}", what, kind(got)))
+}
+
+// prepend `segment` to the path of a conversion error
+fn in_path(err: LuaActorError, segment: String) -> LuaActorError {
+    match err {
+        LuaActorError::Conversion(message) => LuaActorError::FieldConversion {
+            path: segment,
+            message,
+        },
+        LuaActorError::FieldConversion { path, message } => {
+            let path = if path.starts_with('[') {
+                format!("{}{}", segment, path)
+            } else {
+                format!("{}.{}", segment, path)
+            };
+            LuaActorError::FieldConversion { path, message }
+        }
+        e => e,
+    }
+}
+
+// Helpers used by the code generated with `actix-lua-derive`.
+
+#[doc(hidden)]
+pub fn in_field(err: LuaActorError, name: &str) -> LuaActorError {
+    in_path(err, name.to_string())
+}
+
+#[doc(hidden)]
+pub fn table(msg: LuaMessage) -> Result<HashMap<String, LuaMessage>, LuaActorError> {
+    match msg {
+        LuaMessage::Table(t) => Ok(t),
+        msg => Err(expected("table", &msg)),
+    }
+}
+
+#[doc(hidden)]
+pub fn array(msg: LuaMessage, len: usize) -> Result<Vec<LuaMessage>, LuaActorError> {
+    match msg {
+        LuaMessage::Array(ref items) if items.len() != len => Err(LuaActorError::Conversion(
+            format!("expected {} items, got {}", len, items.len()),
+        )),
+        LuaMessage::Array(items) => Ok(items),
+        msg => Err(expected("array", &msg)),
+    }
+}
+
+/// Take the field `name` of a table. Missing fields are `Nil`.
+#[doc(hidden)]
+pub fn field<T: FromLuaMessage>(
+    t: &mut HashMap<String, LuaMessage>,
+    name: &str,
+) -> Result<T, LuaActorError> {
+    let v = t.remove(name).unwrap_or(LuaMessage::Nil);
+    T::from_lua_message(v).map_err(|e| in_field(e, name))
+}
+
+/// Take the `i`th item of an array.
+#[doc(hidden)]
+pub fn element<T: FromLuaMessage>(items: &mut [LuaMessage], i: usize) -> Result<T, LuaActorError> {
+    let v = mem::replace(&mut items[i], LuaMessage::Nil);
+    // lua sequences start at 1
+    T::from_lua_message(v).map_err(|e| in_path(e, format!("[{}]", i + 1)))
+}
+
+#[doc(hidden)]
+pub fn unknown_variant(tag: &str, name: &str) -> LuaActorError {
+    LuaActorError::Conversion(format!("unknown variant `{}` of {}", tag, name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_lua_derive::{FromLuaMessage, IntoLuaMessage};
+
+    #[derive(Debug, PartialEq, IntoLuaMessage, FromLuaMessage)]
+    struct Address {
+        city: String,
+        #[lua(rename = "zipCode")]
+        zip_code: u32,
+    }
+
+    #[derive(Debug, PartialEq, IntoLuaMessage, FromLuaMessage)]
+    #[lua(tag = "kind")]
+    enum Shape {
+        Empty,
+        Circle(f64),
+        Line(i64, i64),
+        #[lua(rename = "rect")]
+        Rect {
+            w: i64,
+            h: i64,
+        },
+    }
+
+    #[derive(Debug, PartialEq, IntoLuaMessage, FromLuaMessage)]
+    struct User {
+        name: String,
+        email: Option<String>,
+        tags: Vec<String>,
+        address: Address,
+        shapes: Vec<Shape>,
+    }
+
+    #[derive(Debug, PartialEq, IntoLuaMessage, FromLuaMessage)]
+    struct Id(i64);
+
+    // field names of the generated code
+    #[derive(Debug, PartialEq, IntoLuaMessage, FromLuaMessage)]
+    enum Shadow {
+        V { t: i64, msg: i64, tag: i64 },
+    }
+
+    fn user() -> User {
+        User {
+            name: "foo".to_string(),
+            email: None,
+            tags: vec!["a".to_string()],
+            address: Address {
+                city: "Taipei".to_string(),
+                zip_code: 100,
+            },
+            shapes: vec![
+                Shape::Empty,
+                Shape::Circle(1.5),
+                Shape::Line(1, 2),
+                Shape::Rect { w: 3, h: 4 },
+            ],
+        }
+    }
+
+    fn get(msg: &LuaMessage, key: &str) -> LuaMessage {
+        match msg {
+            LuaMessage::Table(t) => t[key].clone(),
+            _ => panic!("should be a table"),
+        }
+    }
+
+    #[test]
+    fn derive() {
+        let msg = LuaMessage::from(user());
+        assert_eq!(get(&msg, "email"), LuaMessage::Nil);
+        assert_eq!(get(&get(&msg, "address"), "zipCode"), LuaMessage::from(100));
+        if let LuaMessage::Array(shapes) = get(&msg, "shapes") {
+            assert_eq!(get(&shapes[0], "kind"), LuaMessage::from("Empty"));
+            assert_eq!(get(&shapes[1], "value"), LuaMessage::from(1.5));
+            assert_eq!(get(&shapes[3], "kind"), LuaMessage::from("rect"));
+            assert_eq!(get(&shapes[3], "w"), LuaMessage::from(3));
+        } else {
+            panic!("should be an array");
+        }
+        assert_eq!(User::from_lua_message(msg).unwrap(), user());
+
+        assert_eq!(LuaMessage::from(Id(1)), LuaMessage::from(1));
+        assert_eq!(Id::from_lua_message(LuaMessage::from(1)).unwrap(), Id(1));
+    }
+
+    #[test]
+    fn field_path() {
+        fn error_path(msg: LuaMessage) -> (String, String) {
+            match User::from_lua_message(msg) {
+                Err(LuaActorError::FieldConversion { path, message }) => (path, message),
+                res => panic!("should be a field error: {:?}", res),
+            }
+        }
+
+        let mut msg = LuaMessage::from(user());
+        if let LuaMessage::Table(ref mut t) = msg {
+            t.remove("name");
+        }
+        assert_eq!(
+            error_path(msg),
+            ("name".to_string(), "expected string, got nil".to_string())
+        );
+
+        let mut msg = LuaMessage::from(user());
+        if let LuaMessage::Table(ref mut t) = msg {
+            t.insert("tags".to_string(), LuaMessage::from(vec![1]));
+        }
+        assert_eq!(error_path(msg).0, "tags[1]");
+
+        let mut msg = LuaMessage::from(user());
+        if let LuaMessage::Table(ref mut t) = msg {
+            if let Some(LuaMessage::Table(address)) = t.get_mut("address") {
+                address.insert("zipCode".to_string(), LuaMessage::from(-1));
+            }
+        }
+        let (path, message) = error_path(msg);
+        assert_eq!(path, "address.zipCode");
+        assert_eq!(message, "-1 is out of range for u32");
+
+        let mut msg = LuaMessage::from(user());
+        if let LuaMessage::Table(ref mut t) = msg {
+            if let Some(LuaMessage::Array(shapes)) = t.get_mut("shapes") {
+                shapes[2] = LuaMessage::from(Shape::Line(1, 2));
+                if let LuaMessage::Table(line) = &mut shapes[2] {
+                    line.insert("value".to_string(), LuaMessage::from(vec!["x", "y"]));
+                }
+            }
+        }
+        assert_eq!(error_path(msg).0, "shapes[3].value[1]");
+    }
+
+    #[test]
+    fn unknown_variant() {
+        let mut t = HashMap::new();
+        t.insert("kind".to_string(), LuaMessage::from("Triangle"));
+        let res = Shape::from_lua_message(LuaMessage::from(t));
+        assert!(matches!(res, Err(LuaActorError::Conversion(_))));
+    }
+
+    #[test]
+    fn integral_float() {
+        assert_eq!(u8::from_lua_message(LuaMessage::from(5.0)).unwrap(), 5);
+        assert!(u8::from_lua_message(LuaMessage::from(5.5)).is_err());
+    }
+
+    #[test]
+    fn shadowed_fields() {
+        let v = Shadow::V {
+            t: 1,
+            msg: 2,
+            tag: 3,
+        };
+        let msg = v.into_lua_message();
+        assert_eq!(get(&msg, "t"), LuaMessage::from(1));
+        assert_eq!(
+            Shadow::from_lua_message(msg).unwrap(),
+            Shadow::V {
+                t: 1,
+                msg: 2,
+                tag: 3
+            }
+        );
+    }
+
+    #[test]
+    fn u64_range() {
+        assert_eq!(5u64.into_lua_message(), LuaMessage::from(5));
+        assert_eq!(u64::from_lua_message(LuaMessage::from(5)).unwrap(), 5);
+        assert!(u64::from_lua_message(LuaMessage::from(-1)).is_err());
+        // out of the range of lua integers
+        assert_eq!(
+            u64::MAX.into_lua_message(),
+            LuaMessage::Number(u64::MAX as f64)
+        );
+    }
+}
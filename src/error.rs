@@ -0,0 +1,237 @@
+use ::actix::MailboxError;
+use rlua::Error as LuaError;
+
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+
+/// Errors returned by a `LuaActor` and its scripts.
+#[derive(Debug)]
+pub enum LuaActorError {
+    /// A script failed to compile.
+    Compile(String),
+    /// A script raised an error while running.
+    Runtime { message: String, traceback: String },
+    /// A value can't be converted from/to `LuaMessage`.
+    Conversion(String),
+    /// A field of a value can't be converted from `LuaMessage`.
+    /// `path` is the location of the field, e.g. `user.tags[1]`.
+    FieldConversion { path: String, message: String },
+    /// A recipient name is neither a recipient of the actor nor registered in `LuaRegistry`.
+    /// `ctx.send` returns the error message and `ctx.do_send` logs it.
+    MissingRecipient(String),
+    /// A message can't be delivered to a recipient.
+    Mailbox(MailboxError),
+    /// A handler exceeded the instruction budget set with `max_instructions_per_message`.
+    InstructionLimit,
+    /// A handler exceeded the time limit set with `handler_timeout`.
+    Timeout,
+    /// The lua VM exceeded the memory limit set with `memory_limit`.
+    OutOfMemory,
+    /// `ctx.spawn` exceeded the number of children set with `max_children`.
+    ChildLimit(usize),
+    /// A suspended coroutine was evicted by the limits set with `max_suspended_threads`
+    /// or `max_suspended_age`.
+    Evicted,
+    /// A script file can't be read.
+    Io(io::Error),
+}
+
+impl LuaActorError {
+    // Lua errors carry their traceback at the end of the message.
+    // errors rethrown from a coroutine carry the traceback of the coroutine first,
+    // followed by the one of the prelude which is left out.
+    fn runtime(msg: &str) -> LuaActorError {
+        const TRACEBACK: &str = "\nstack traceback:\n";
+        let (message, traceback) = match msg.find(TRACEBACK) {
+            Some(i) => {
+                let traceback = &msg[i + 1..];
+                match traceback[1..].find(TRACEBACK) {
+                    Some(j) => (&msg[..i], &traceback[..j + 1]),
+                    None => (&msg[..i], traceback),
+                }
+            }
+            None => (msg, ""),
+        };
+        LuaActorError::Runtime {
+            message: message.to_string(),
+            traceback: traceback.to_string(),
+        }
+    }
+
+    pub(crate) fn compile(err: LuaError) -> LuaActorError {
+        match err {
+            LuaError::SyntaxError { message, .. } => LuaActorError::Compile(message),
+            LuaError::RuntimeError(msg) => match LuaActorError::runtime(&msg) {
+                LuaActorError::Runtime { message, .. } => LuaActorError::Compile(message),
+                e => e,
+            },
+            e => LuaActorError::from(e),
+        }
+    }
+}
+
+// `MailboxError` and `io::Error` don't implement `Clone`
+impl Clone for LuaActorError {
+    fn clone(&self) -> LuaActorError {
+        match self {
+            LuaActorError::Compile(msg) => LuaActorError::Compile(msg.clone()),
+            LuaActorError::Runtime { message, traceback } => LuaActorError::Runtime {
+                message: message.clone(),
+                traceback: traceback.clone(),
+            },
+            LuaActorError::Conversion(msg) => LuaActorError::Conversion(msg.clone()),
+            LuaActorError::FieldConversion { path, message } => LuaActorError::FieldConversion {
+                path: path.clone(),
+                message: message.clone(),
+            },
+            LuaActorError::MissingRecipient(name) => LuaActorError::MissingRecipient(name.clone()),
+            LuaActorError::Mailbox(MailboxError::Closed) => {
+                LuaActorError::Mailbox(MailboxError::Closed)
+            }
+            LuaActorError::Mailbox(MailboxError::Timeout) => {
+                LuaActorError::Mailbox(MailboxError::Timeout)
+            }
+            LuaActorError::InstructionLimit => LuaActorError::InstructionLimit,
+            LuaActorError::Timeout => LuaActorError::Timeout,
+            LuaActorError::OutOfMemory => LuaActorError::OutOfMemory,
+            LuaActorError::ChildLimit(max) => LuaActorError::ChildLimit(*max),
+            LuaActorError::Evicted => LuaActorError::Evicted,
+            LuaActorError::Io(e) => LuaActorError::Io(io::Error::new(e.kind(), e.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for LuaActorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LuaActorError::Compile(msg) => write!(f, "compile error: {}", msg),
+            LuaActorError::Runtime { message, .. } => write!(f, "runtime error: {}", message),
+            LuaActorError::Conversion(msg) => write!(f, "conversion error: {}", msg),
+            LuaActorError::FieldConversion { path, message } => {
+                write!(f, "conversion error at `{}`: {}", path, message)
+            }
+            LuaActorError::MissingRecipient(name) => write!(f, "recipient not found: {}", name),
+            LuaActorError::Mailbox(e) => write!(f, "mailbox error: {}", e),
+            LuaActorError::InstructionLimit => write!(f, "instruction limit exceeded"),
+            LuaActorError::Timeout => write!(f, "handler timed out"),
+            LuaActorError::OutOfMemory => write!(f, "out of memory"),
+            LuaActorError::ChildLimit(max) => write!(f, "more than {} children", max),
+            LuaActorError::Evicted => write!(f, "suspended coroutine evicted"),
+            LuaActorError::Io(e) => write!(f, "io error: {}", e),
+        }
+    }
+}
+
+impl StdError for LuaActorError {}
+
+impl From<LuaError> for LuaActorError {
+    fn from(err: LuaError) -> LuaActorError {
+        match err {
+            LuaError::SyntaxError { message, .. } => LuaActorError::Compile(message),
+            LuaError::RuntimeError(msg) => LuaActorError::runtime(&msg),
+            LuaError::MemoryError(_) => LuaActorError::OutOfMemory,
+            LuaError::ToLuaConversionError { .. } | LuaError::FromLuaConversionError { .. } => {
+                LuaActorError::Conversion(err.to_string())
+            }
+            LuaError::ExternalError(ref e) => match e.downcast_ref::<LuaActorError>() {
+                Some(e) => e.clone(),
+                None => LuaActorError::Runtime {
+                    message: e.to_string(),
+                    traceback: String::new(),
+                },
+            },
+            LuaError::CallbackError { traceback, cause } => {
+                // recover errors raised by the context API and hooks
+                if let LuaError::ExternalError(ref e) = *cause {
+                    if let Some(e) = e.downcast_ref::<LuaActorError>() {
+                        return e.clone();
+                    }
+                }
+                // errors of coroutines carry the traceback of the coroutine
+                match LuaActorError::from((*cause).clone()) {
+                    LuaActorError::Runtime {
+                        message,
+                        traceback: ref inner,
+                    } if inner.is_empty() => LuaActorError::Runtime { message, traceback },
+                    e => e,
+                }
+            }
+            e => LuaActorError::Runtime {
+                message: e.to_string(),
+                traceback: String::new(),
+            },
+        }
+    }
+}
+
+impl From<io::Error> for LuaActorError {
+    fn from(err: io::Error) -> LuaActorError {
+        LuaActorError::Io(err)
+    }
+}
+
+impl From<LuaActorError> for LuaError {
+    fn from(err: LuaActorError) -> LuaError {
+        LuaError::external(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runtime_traceback() {
+        let err = LuaActorError::from(LuaError::RuntimeError(
+            "handle:1: foo\nstack traceback:\n\t[C]: in ?".to_string(),
+        ));
+        if let LuaActorError::Runtime { message, traceback } = err {
+            assert_eq!(message, "handle:1: foo");
+            assert_eq!(traceback, "stack traceback:\n\t[C]: in ?");
+        } else {
+            panic!("should be a runtime error");
+        }
+    }
+
+    #[test]
+    fn coroutine_traceback() {
+        let err = LuaActorError::from(LuaError::RuntimeError(
+            "handle:1: foo\nstack traceback:\n\thandle:1\nstack traceback:\n\tPrelude:1"
+                .to_string(),
+        ));
+        if let LuaActorError::Runtime { message, traceback } = err {
+            assert_eq!(message, "handle:1: foo");
+            assert_eq!(traceback, "stack traceback:\n\thandle:1");
+        } else {
+            panic!("should be a runtime error");
+        }
+    }
+
+    #[test]
+    fn memory_error_message() {
+        // only `MemoryError` is out of memory, not a script raising the same message
+        let err = LuaActorError::from(LuaError::RuntimeError("not enough memory".to_string()));
+        assert!(matches!(err, LuaActorError::Runtime { .. }));
+        let err = LuaActorError::from(LuaError::CallbackError {
+            traceback: "stack traceback:".to_string(),
+            cause: std::sync::Arc::new(LuaError::MemoryError("not enough memory".to_string())),
+        });
+        assert!(matches!(err, LuaActorError::OutOfMemory));
+    }
+
+    #[test]
+    fn callback_error() {
+        let err = LuaActorError::from(LuaError::CallbackError {
+            traceback: "stack traceback:".to_string(),
+            cause: std::sync::Arc::new(LuaError::from(LuaActorError::MissingRecipient(
+                "foo".to_string(),
+            ))),
+        });
+        if let LuaActorError::MissingRecipient(name) = err {
+            assert_eq!(name, "foo");
+        } else {
+            panic!("should be a missing recipient error");
+        }
+    }
+}
@@ -0,0 +1,206 @@
+//! Native `json` module for scripts, enabled with the `json` feature.
+use rlua::{Context, Error as LuaError, FromLua, LightUserData, Result as LuaResult, Table, Value};
+use serde_json::{Map, Number, Value as Json};
+
+use std::ptr;
+
+use crate::error::LuaActorError;
+use crate::message::{is_sequence, type_name};
+
+// `json.null`, a null light userdata which is converted to `LuaMessage::Nil`
+fn null<'lua>() -> Value<'lua> {
+    Value::LightUserData(LightUserData(ptr::null_mut()))
+}
+
+fn error(msg: String) -> LuaError {
+    LuaActorError::Conversion(msg).into()
+}
+
+/// Register the `json` module as a global, and in `package.loaded` if `package` is available.
+pub(crate) fn preload(ctx: Context) -> LuaResult<()> {
+    let json = ctx.create_table()?;
+    json.set("null", null())?;
+    json.set(
+        "encode",
+        ctx.create_function(|ctx, v: Value| {
+            let seen = ctx.create_table()?;
+            Ok(to_json(v, ctx, &seen)?.to_string())
+        })?,
+    )?;
+    json.set(
+        "decode",
+        ctx.create_function(|ctx, s: rlua::String| {
+            let j: Json = serde_json::from_slice(s.as_bytes())
+                .map_err(|e| error(format!("json.decode: {}", e)))?;
+            from_json(j, ctx)
+        })?,
+    )?;
+
+    let globals = ctx.globals();
+    if let Value::Table(package) = globals.get::<_, Value>("package")? {
+        if let Ok(loaded) = package.get::<_, Table>("loaded") {
+            loaded.set("json", json.clone())?;
+        }
+    }
+    globals.set("json", json)
+}
+
+// `seen` contains the tables being encoded, to detect cycles
+fn to_json<'lua>(v: Value<'lua>, ctx: Context<'lua>, seen: &Table<'lua>) -> LuaResult<Json> {
+    match v {
+        Value::Nil => Ok(Json::Null),
+        Value::LightUserData(ud) if ud.0.is_null() => Ok(Json::Null),
+        Value::Boolean(b) => Ok(Json::Bool(b)),
+        Value::Integer(n) => Ok(Json::from(n)),
+        Value::Number(n) => Number::from_f64(n)
+            .map(Json::Number)
+            .ok_or_else(|| error(format!("json.encode: can't encode {}", n))),
+        Value::String(s) => match s.to_str() {
+            Ok(s) => Ok(Json::String(s.to_string())),
+            Err(_) => Err(error("json.encode: string is not valid UTF-8".to_string())),
+        },
+        Value::Table(t) => table_to_json(t, ctx, seen),
+        v => Err(error(format!(
+            "json.encode: can't encode a {}",
+            type_name(&v)
+        ))),
+    }
+}
+
+fn table_to_json<'lua>(t: Table<'lua>, ctx: Context<'lua>, seen: &Table<'lua>) -> LuaResult<Json> {
+    if seen.raw_get::<_, bool>(t.clone())? {
+        return Err(error("json.encode: table contains a cycle".to_string()));
+    }
+    seen.raw_set(t.clone(), true)?;
+
+    let pairs = t
+        .clone()
+        .pairs::<Value, Value>()
+        .collect::<LuaResult<Vec<(Value, Value)>>>()?;
+    let json = if is_sequence(&pairs) {
+        let mut items = vec![Json::Null; pairs.len()];
+        for (k, v) in pairs {
+            if let Value::Integer(i) = k {
+                items[(i - 1) as usize] = to_json(v, ctx, seen)?;
+            }
+        }
+        Json::Array(items)
+    } else {
+        let mut map = Map::new();
+        for (k, v) in pairs {
+            let name = type_name(&k);
+            let k = String::from_lua(k, ctx).map_err(|_| {
+                error(format!(
+                    "json.encode: can't use a {} as an object key",
+                    name
+                ))
+            })?;
+            map.insert(k, to_json(v, ctx, seen)?);
+        }
+        Json::Object(map)
+    };
+
+    // the same table may appear again outside of itself
+    seen.raw_set(t, Value::Nil)?;
+    Ok(json)
+}
+
+fn from_json(j: Json, ctx: Context) -> LuaResult<Value> {
+    match j {
+        Json::Null => Ok(null()),
+        Json::Bool(b) => Ok(Value::Boolean(b)),
+        Json::Number(n) => match n.as_i64() {
+            Some(i) => Ok(Value::Integer(i)),
+            None => Ok(Value::Number(n.as_f64().unwrap_or(f64::NAN))),
+        },
+        Json::String(s) => ctx.create_string(&s).map(Value::String),
+        Json::Array(a) => {
+            let t = ctx.create_table()?;
+            for (i, v) in a.into_iter().enumerate() {
+                t.raw_set(i as i64 + 1, from_json(v, ctx)?)?;
+            }
+            Ok(Value::Table(t))
+        }
+        Json::Object(o) => {
+            let t = ctx.create_table()?;
+            for (k, v) in o {
+                t.raw_set(k, from_json(v, ctx)?)?;
+            }
+            Ok(Value::Table(t))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::LuaMessage;
+    use rlua::Lua;
+
+    fn eval(code: &str) -> LuaResult<LuaMessage> {
+        let vm = Lua::new();
+        vm.context(|ctx| {
+            preload(ctx)?;
+            ctx.load(code).eval()
+        })
+    }
+
+    fn eval_err(code: &str) -> String {
+        LuaActorError::from(eval(code).unwrap_err()).to_string()
+    }
+
+    #[test]
+    fn encode() {
+        assert_eq!(
+            eval(r#"return json.encode({1, 2.5, "a", json.null, true})"#).unwrap(),
+            LuaMessage::from(r#"[1,2.5,"a",null,true]"#)
+        );
+        assert_eq!(
+            eval(r#"return json.encode({a = {b = 1.0}, [2] = "x"})"#).unwrap(),
+            LuaMessage::from(r#"{"2":"x","a":{"b":1.0}}"#)
+        );
+        assert_eq!(
+            eval(r#"return json.encode({})"#).unwrap(),
+            LuaMessage::from("{}")
+        );
+        assert_eq!(
+            eval(r#"local t = {}; return json.encode({t, t})"#).unwrap(),
+            LuaMessage::from("[{},{}]")
+        );
+    }
+
+    #[test]
+    fn encode_error() {
+        let err = eval_err(r#"local t = {}; t.t = t; return json.encode(t)"#);
+        assert!(err.contains("table contains a cycle"));
+        let err = eval_err(r#"return json.encode({f = print})"#);
+        assert!(err.contains("can't encode a function"));
+        let err = eval_err(r#"return json.encode(0/0)"#);
+        assert!(err.contains("can't encode"));
+    }
+
+    #[test]
+    fn decode() {
+        assert_eq!(
+            eval(r#"local v = json.decode("[1, 1.0, null, 3]"); return {math.type(v[1]), math.type(v[2]), v[3] == json.null, #v}"#)
+                .unwrap(),
+            LuaMessage::from(vec![
+                LuaMessage::from("integer"),
+                LuaMessage::from("float"),
+                LuaMessage::from(true),
+                LuaMessage::from(4),
+            ])
+        );
+
+        let mut t = std::collections::HashMap::new();
+        t.insert("a".to_string(), LuaMessage::Nil);
+        t.insert("b".to_string(), LuaMessage::from(vec!["x"]));
+        assert_eq!(
+            eval(r#"return json.decode('{"a": null, "b": ["x"]}')"#).unwrap(),
+            LuaMessage::from(t)
+        );
+
+        let err = eval_err(r#"return json.decode("{")"#);
+        assert!(err.contains("json.decode"));
+    }
+}
@@ -27,21 +27,65 @@
 //! [`LuaActor`] can only send/receive messages with type [`LuaMessage`].
 //! It can be converted from/to primitive types such as `i64`, `String`, and `HashMap` with `LuaMessage::from`.
 //!
+//! With the `serde` feature, `LuaMessage` implements `Serialize` and `Deserialize`.
+//! Use `LuaMessage::from_serialize` and `LuaMessage::deserialize_into` to send and receive your own types.
+//!
+//! With the `derive` feature, `#[derive(IntoLuaMessage, FromLuaMessage)]` implements the conversions for your own types.
+//!
+//! Wrap a message in [`LuaCall`] to receive script errors as [`LuaActorError`] instead of `LuaMessage::Nil`.
+//!
 //! [actix]: https://github.com/actix/actix
 //! [Lua programming language]: https://www.lua.org
 //! [`LuaActor`]: struct.LuaActor.html
 //! [`LuaActorBuilder`]: struct.LuaActorBuilder.html
 //! [`LuaMessage`]: enum.LuaMessage.html
+//! [`LuaCall`]: struct.LuaCall.html
+//! [`LuaActorError`]: enum.LuaActorError.html
 #[cfg(test)]
 extern crate futures_timer;
+// the code generated by `actix-lua-derive` refers to `::actix_lua`
+#[cfg(test)]
+extern crate self as actix_lua;
 
 mod actor;
 mod builder;
+mod convert;
+mod error;
+#[cfg(feature = "json")]
+mod json;
+mod limits;
 mod message;
+#[cfg(feature = "msgpack")]
+mod msgpack;
+mod persist;
+mod registry;
+mod sandbox;
+#[cfg(feature = "serde")]
+mod serialize;
+mod watcher;
 
-pub use crate::actor::LuaActor;
+pub use crate::actor::{
+    AddRecipient, GetStats, LuaActor, LuaActorStats, ReloadScripts, RemoveRecipient, SnapshotState,
+    SuspendedThread, UsedMemory,
+};
 pub use crate::builder::LuaActorBuilder;
-pub use crate::message::LuaMessage;
+pub use crate::convert::{FromLuaMessage, IntoLuaMessage};
+pub use crate::error::LuaActorError;
+pub use crate::message::{LuaCall, LuaMessage, LuaReply, Utf8Policy};
+#[cfg(feature = "msgpack")]
+pub use crate::msgpack::LuaMessageCodec;
+pub use crate::persist::PersistPolicy;
+pub use crate::registry::{Lookup, LuaRegistry, Register, Unregister};
+pub use crate::sandbox::SandboxProfile;
+
+#[cfg(feature = "derive")]
+pub use actix_lua_derive::{FromLuaMessage, IntoLuaMessage};
+
+/// Helpers used by the code generated with `#[derive(IntoLuaMessage, FromLuaMessage)]`.
+#[doc(hidden)]
+pub mod __private {
+    pub use crate::convert::{array, element, field, in_field, table, unknown_variant};
+}
 
 /// Re-export `rlua` interface for library developers
 pub mod dev {
@@ -0,0 +1,121 @@
+use rlua::{Context, HookTriggers, Lua};
+
+use crate::error::LuaActorError;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// how often the hook checks the limits when only `timeout` is set
+const CHECK_INTERVAL: u32 = 1000;
+
+/// Resource limits of a `LuaActor`.
+///
+/// The instruction budget and the timeout are enforced with a lua hook and reset before each handler runs.
+/// Once exceeded, the error is raised again whenever the script catches it, until the next reset.
+/// The memory limit applies to the whole VM.
+#[derive(Clone, Default)]
+pub(crate) struct Limits {
+    max_instructions: Option<u32>,
+    timeout: Option<Duration>,
+    memory: Option<usize>,
+    usage: Arc<Mutex<Usage>>,
+}
+
+#[derive(Default)]
+struct Usage {
+    instructions: u64,
+    deadline: Option<Instant>,
+}
+
+impl Usage {
+    // the limit exceeded by the current handler. stays exceeded until the next reset
+    fn exceeded(&self, max_instructions: Option<u32>) -> Option<LuaActorError> {
+        if let Some(max) = max_instructions {
+            if self.instructions >= u64::from(max) {
+                return Some(LuaActorError::InstructionLimit);
+            }
+        }
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => Some(LuaActorError::Timeout),
+            _ => None,
+        }
+    }
+}
+
+impl Limits {
+    pub fn new(
+        max_instructions: Option<u32>,
+        timeout: Option<Duration>,
+        memory: Option<usize>,
+    ) -> Limits {
+        Limits {
+            max_instructions,
+            timeout,
+            memory,
+            usage: Arc::new(Mutex::new(Usage::default())),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.max_instructions.is_none() && self.timeout.is_none()
+    }
+
+    /// Apply the limits to the given VM. Replaces any hook set previously.
+    pub fn install(&self, vm: &Lua) -> Result<(), LuaActorError> {
+        if self.memory.is_some() {
+            vm.set_memory_limit(self.memory);
+        }
+
+        if self.is_empty() {
+            return Ok(());
+        }
+
+        let step = self
+            .max_instructions
+            .map_or(CHECK_INTERVAL, |max| max.clamp(1, CHECK_INTERVAL));
+        let max_instructions = self.max_instructions;
+        let usage = self.usage.clone();
+        vm.set_hook(
+            HookTriggers {
+                every_nth_instruction: Some(step),
+                ..Default::default()
+            },
+            move |_, _| {
+                let mut usage = usage.lock().unwrap();
+                usage.instructions += u64::from(step);
+                match usage.exceeded(max_instructions) {
+                    Some(e) => Err(e.into()),
+                    None => Ok(()),
+                }
+            },
+        );
+
+        vm.context(|ctx| self.guard(ctx))
+    }
+
+    // wrap the functions catching errors to raise the exceeded limit again
+    fn guard(&self, ctx: Context) -> Result<(), LuaActorError> {
+        let max_instructions = self.max_instructions;
+        let usage = self.usage.clone();
+        let check = ctx.create_function(move |_, ()| {
+            match usage.lock().unwrap().exceeded(max_instructions) {
+                Some(e) => Err(e.into()),
+                None => Ok(()),
+            }
+        })?;
+        ctx.load(include_str!("lua/limits.lua"))
+            .set_name("Limits")?
+            .call::<_, ()>(check)?;
+        Ok(())
+    }
+
+    /// Start a new budget for the next handler.
+    pub fn reset(&self) {
+        if self.is_empty() {
+            return;
+        }
+
+        let mut usage = self.usage.lock().unwrap();
+        usage.instructions = 0;
+        usage.deadline = self.timeout.map(|t| Instant::now() + t);
+    }
+}
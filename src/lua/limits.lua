@@ -0,0 +1,26 @@
+-- `pcall`, `xpcall` and `coroutine.resume` catch the errors raised by the hook.
+-- raise them again once a limit is exceeded, so a script can't keep running by catching them.
+local check = ...
+local raw_pcall, raw_xpcall = pcall, xpcall
+
+local function rethrow(ok, ...)
+    if not ok then
+        check()
+    end
+    return ok, ...
+end
+
+function pcall(...)
+    return rethrow(raw_pcall(...))
+end
+
+function xpcall(...)
+    return rethrow(raw_xpcall(...))
+end
+
+if coroutine then
+    local raw_resume = coroutine.resume
+    coroutine.resume = function (...)
+        return rethrow(raw_resume(...))
+    end
+end
@@ -1,58 +1,149 @@
+-- resume a coroutine and raise its error with the traceback of the coroutine
+local resume = ...
+-- `pcall` before it's replaced by the limits
+local pcall = pcall
+
 __threads = {}
 __thread_id_seq = 0
 __scripts = {}
 
 ctx = { state = {} }
 
-function __load(script, name)
-    local f, err = load(script, name, "bt")
-    if f == nil then
-        error(err)
-    end
+-- yielded by coroutines waiting for a reply. scripts can't yield it themselves
+local SUSPENDED = {}
+
+-- register a compiled script
+function __load(f, name)
     __scripts[name] = f
 end
 
--- create a new coroutine from given script
+-- a copy of `value` and all the tables it contains
+local function deep_copy(value, copies)
+    if type(value) ~= "table" then
+        return value
+    end
+    if copies[value] then
+        return copies[value]
+    end
+    local copy = {}
+    copies[value] = copy
+    for k, v in pairs(value) do
+        copy[deep_copy(k, copies)] = deep_copy(v, copies)
+    end
+    return setmetatable(copy, getmetatable(value))
+end
+
+-- run the migrate script with a copy of the current state, `ctx.state` is kept as-is if it fails.
+-- the returned table, or the copy if nothing is returned, replaces `ctx.state`
+function __migrate()
+    local migrate = __scripts["migrate"]
+    __scripts["migrate"] = nil
+    local copy = deep_copy(ctx.state, {})
+    local state = migrate(copy)
+    if type(state) == "table" then
+        ctx.state = state
+    else
+        ctx.state = copy
+    end
+end
+
+-- suspend the current coroutine until it's resumed with a reply, or an error to raise in the caller
+function __await()
+    local ret, err = coroutine.yield(SUSPENDED)
+    if err ~= nil then
+        raise_resume_error()
+        error(err, 3)
+    end
+    return ret
+end
+
+-- the id of the current coroutine if it's waiting for a reply
+local function suspended(thread, ret)
+    if ret == SUSPENDED and coroutine.status(thread) == "suspended" then
+        return ctx.thread_id
+    end
+end
+
+-- create a new coroutine from given script.
+-- returns the result, and the id of the coroutine if it's suspended instead
 function __run(script_name, msg, thread_id)
     ctx.thread_id = __thread_id_seq
     __thread_id_seq = __thread_id_seq + 1
 
     ctx.notify = notify
     ctx.notify_later = notify_later
-    ctx.send = function (recipient_name, msg)
-        send(recipient_name, msg, ctx.thread_id)
-        return coroutine.yield("__suspended__" .. ctx.thread_id)
+    ctx.run_later = run_later
+    ctx.run_interval = run_interval
+    ctx.cancel = cancel
+    ctx.send = function (recipient_name, msg, options)
+        send(recipient_name, msg, ctx.thread_id, options)
+        -- resumed with the response, or nil and the error
+        return coroutine.yield(SUSPENDED)
+    end
+    ctx.gather = function (requests)
+        gather(requests, false, ctx.thread_id)
+        local ret = __await()
+        return ret[1], ret[2]
+    end
+    ctx.race = function (requests)
+        gather(requests, true, ctx.thread_id)
+        local ret = __await()
+        return ret[1], ret[2]
     end
     ctx.do_send = do_send
+    ctx.sleep = function (secs)
+        sleep(secs, ctx.thread_id)
+        __await()
+    end
+    ctx.recipients = recipients
+    ctx.has_recipient = has_recipient
+    ctx.register = register
+    ctx.register_global = register_global
+    ctx.spawn = spawn
+    ctx.spawn_file = spawn_file
     ctx.terminate = terminate
+    ctx.self = self_recipient
 
     ctx.msg = msg
 
     local thread = coroutine.create(__scripts[script_name])
 
-    local ok, ret = coroutine.resume(thread)
+    local ok, ret = pcall(resume, thread)
     if not ok then
-        error(ret)
+        ctx.msg = nil
+        ctx.thread_id = nil
+        error(ret, 0)
     end
     -- save the thread and its context if the thread yielded
     if coroutine.status(thread) == "suspended" then
         __threads[ctx.thread_id] = { thread = thread, msg = msg }
     end
+    local tid = suspended(thread, ret)
     ctx.msg = nil
     ctx.thread_id = nil
+    if tid then
+        return nil, tid
+    end
     return ret
 end
 
--- resume a existing coroutine
-function __resume(thread_id, args)
+-- resume a existing coroutine. `err` is raised in the coroutine if it's not nil
+function __resume(thread_id, args, err)
     local thread = __threads[thread_id]
     ctx.thread_id = thread_id
     ctx.msg = thread.msg
-    local ok, ret = coroutine.resume(thread.thread, args)
+    local ok, ret = pcall(resume, thread.thread, args, err)
     if coroutine.status(thread.thread) == "dead" then
         __threads[ctx.thread_id] = nil
     end
+    local tid = ok and suspended(thread.thread, ret)
     ctx.msg = nil
     ctx.thread_id = nil
+    if not ok then
+        error(ret, 0)
+    end
+    if tid then
+        return nil, tid
+    end
     return ret
 end
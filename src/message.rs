@@ -1,12 +1,39 @@
 use ::actix::dev::{MessageResponse, ResponseChannel};
 use ::actix::prelude::*;
-use regex::Regex;
+use futures::future;
+use futures::sync::oneshot;
+use futures::Future;
+use log::error;
 use rlua::Result as LuaResult;
-use rlua::{Context, FromLua, ToLua, Value};
+use rlua::{
+    AnyUserData, Context, Error as LuaError, FromLua, MetaMethod, Table, ToLua, UserData,
+    UserDataMethods, Value,
+};
 
+use crate::error::LuaActorError;
 use std::collections::HashMap;
+use std::fmt;
+use std::str;
 
-#[derive(Debug, PartialEq, Clone)]
+/// Messages sent to and received from `LuaActor`.
+///
+/// Lua tables are converted with the following rules:
+///
+/// * A non-empty table whose keys are exactly `1..n` is an `Array`.
+/// * Any other table is a `Table`. Number keys are converted to strings, e.g. `{[1] = "a", x = "b"}`
+///   becomes `{"1": "a", "x": "b"}`. Tables with other types of keys can't be converted.
+/// * The empty table is an empty `Table`.
+///
+/// An `Array` is converted to a 1-based Lua sequence.
+///
+/// A null light userdata, such as `json.null`, is converted to `Nil`.
+///
+/// Lua strings which are not valid UTF-8 are converted to `Bytes`, or rejected, depending on
+/// the `Utf8Policy` of the actor. `Bytes` is converted to a Lua string as-is.
+///
+/// A `Recipient` is passed to Lua as userdata, which can be used in place of a recipient name
+/// in `ctx.send` and `ctx.do_send`.
+#[derive(PartialEq, Clone)]
 pub enum LuaMessage {
     String(String),
     Integer(i64),
@@ -14,7 +41,69 @@ pub enum LuaMessage {
     Boolean(bool),
     Nil,
     Table(HashMap<String, LuaMessage>),
+    Array(Vec<LuaMessage>),
+    Bytes(Vec<u8>),
     ThreadYield(String),
+    Recipient(Recipient<LuaMessage>),
+}
+
+// `Recipient` doesn't implement `Debug`
+impl fmt::Debug for LuaMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LuaMessage::String(s) => f.debug_tuple("String").field(s).finish(),
+            LuaMessage::Integer(n) => f.debug_tuple("Integer").field(n).finish(),
+            LuaMessage::Number(n) => f.debug_tuple("Number").field(n).finish(),
+            LuaMessage::Boolean(b) => f.debug_tuple("Boolean").field(b).finish(),
+            LuaMessage::Nil => f.write_str("Nil"),
+            LuaMessage::Table(t) => f.debug_tuple("Table").field(t).finish(),
+            LuaMessage::Array(a) => f.debug_tuple("Array").field(a).finish(),
+            LuaMessage::Bytes(b) => f.debug_tuple("Bytes").field(b).finish(),
+            LuaMessage::ThreadYield(tid) => f.debug_tuple("ThreadYield").field(tid).finish(),
+            LuaMessage::Recipient(_) => f.write_str("Recipient"),
+        }
+    }
+}
+
+/// The userdata of `LuaMessage::Recipient` in Lua.
+struct LuaRecipient(Recipient<LuaMessage>);
+
+impl UserData for LuaRecipient {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_meta_method(MetaMethod::Eq, |_, this, other: AnyUserData| {
+            Ok(match other.borrow::<LuaRecipient>() {
+                Ok(other) => this.0 == other.0,
+                Err(_) => false,
+            })
+        });
+        methods.add_meta_method(MetaMethod::ToString, |_, _, ()| Ok("recipient"));
+    }
+}
+
+/// How a `LuaActor` converts Lua strings which are not valid UTF-8.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Utf8Policy {
+    /// Convert to `LuaMessage::Bytes`.
+    #[default]
+    Bytes,
+    /// Fail the conversion.
+    Error,
+}
+
+// registry key of the policy, the policy is stored in the VM since `FromLua` has no other state
+const UTF8_POLICY_KEY: &str = "actix_lua_utf8_policy";
+
+impl Utf8Policy {
+    pub(crate) fn install(self, ctx: Context) -> LuaResult<()> {
+        ctx.set_named_registry_value(UTF8_POLICY_KEY, self == Utf8Policy::Error)
+    }
+
+    fn current(ctx: Context) -> Utf8Policy {
+        match ctx.named_registry_value::<_, Option<bool>>(UTF8_POLICY_KEY) {
+            Ok(Some(true)) => Utf8Policy::Error,
+            _ => Utf8Policy::Bytes,
+        }
+    }
 }
 
 impl<A, M> MessageResponse<A, M> for LuaMessage
@@ -29,10 +118,74 @@ where
     }
 }
 
+/// Response of a `LuaActor` message handler.
+///
+/// A handler which yields in `ctx.send` can't reply right away.
+/// Its response is deferred until the suspended coroutine returns.
+pub enum LuaReply {
+    Ready(Result<LuaMessage, LuaActorError>),
+    Deferred(oneshot::Receiver<Result<LuaMessage, LuaActorError>>),
+}
+
+impl LuaReply {
+    pub(crate) fn into_future(self) -> ResponseFuture<LuaMessage, LuaActorError> {
+        match self {
+            LuaReply::Ready(res) => Box::new(future::result(res)),
+            // the reply channel is dropped if the suspended coroutine never returns
+            LuaReply::Deferred(rx) => Box::new(rx.then(|res| match res {
+                Ok(res) => res,
+                Err(_) => Err(LuaActorError::Mailbox(MailboxError::Closed)),
+            })),
+        }
+    }
+}
+
+impl<A, M> MessageResponse<A, M> for LuaReply
+where
+    A: Actor,
+    M: Message<Result = LuaMessage>,
+{
+    fn handle<R: ResponseChannel<M>>(self, _: &mut A::Context, tx: Option<R>) {
+        match self {
+            LuaReply::Ready(res) => {
+                if let Some(tx) = tx {
+                    tx.send(or_nil(res));
+                }
+            }
+            reply => {
+                Arbiter::spawn(reply.into_future().then(move |res| {
+                    if let Some(tx) = tx {
+                        tx.send(or_nil(res));
+                    }
+                    Ok(())
+                }));
+            }
+        }
+    }
+}
+
+// `LuaMessage` can't carry the error of a failed handler, it's logged instead
+fn or_nil(res: Result<LuaMessage, LuaActorError>) -> LuaMessage {
+    res.unwrap_or_else(|e| {
+        error!("lua actor handler failed: {}", e);
+        LuaMessage::Nil
+    })
+}
+
 impl Message for LuaMessage {
     type Result = LuaMessage;
 }
 
+/// A `LuaMessage` which replies with `Result<LuaMessage, LuaActorError>`.
+///
+/// Unlike `LuaMessage`, a failed handler replies with the error instead of `LuaMessage::Nil`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct LuaCall(pub LuaMessage);
+
+impl Message for LuaCall {
+    type Result = Result<LuaMessage, LuaActorError>;
+}
+
 impl From<bool> for LuaMessage {
     fn from(s: bool) -> Self {
         LuaMessage::Boolean(s)
@@ -87,6 +240,24 @@ impl From<HashMap<String, LuaMessage>> for LuaMessage {
     }
 }
 
+impl<'l> From<&'l [u8]> for LuaMessage {
+    fn from(s: &'l [u8]) -> Self {
+        LuaMessage::Bytes(s.to_vec())
+    }
+}
+
+impl From<Recipient<LuaMessage>> for LuaMessage {
+    fn from(r: Recipient<LuaMessage>) -> Self {
+        LuaMessage::Recipient(r)
+    }
+}
+
+impl<T: Into<LuaMessage>> From<Vec<T>> for LuaMessage {
+    fn from(s: Vec<T>) -> Self {
+        LuaMessage::Array(s.into_iter().map(Into::into).collect())
+    }
+}
+
 macro_rules! lua_message_convert_float {
     ($x:ty) => {
         impl From<$x> for LuaMessage {
@@ -104,25 +275,113 @@ impl<'lua> FromLua<'lua> for LuaMessage {
     fn from_lua(v: Value<'lua>, ctx: Context<'lua>) -> LuaResult<LuaMessage> {
         match v {
             Value::String(x) => {
-                let re = Regex::new(r"__suspended__(.+)").unwrap();
-                let s = Value::String(x);
-                if let Some(cap) = re.captures(&String::from_lua(s.clone(), ctx)?) {
-                    let tid = cap.get(1).unwrap().as_str();
-                    Ok(LuaMessage::ThreadYield(tid.to_string()))
-                } else {
-                    Ok(LuaMessage::String(String::from_lua(s.clone(), ctx)?))
-                }
+                let s = match str::from_utf8(x.as_bytes()) {
+                    Ok(s) => s,
+                    Err(e) => {
+                        return match Utf8Policy::current(ctx) {
+                            Utf8Policy::Bytes => Ok(LuaMessage::Bytes(x.as_bytes().to_vec())),
+                            Utf8Policy::Error => Err(LuaError::FromLuaConversionError {
+                                from: "string",
+                                to: "LuaMessage",
+                                message: Some(e.to_string()),
+                            }),
+                        };
+                    }
+                };
+                Ok(LuaMessage::String(s.to_string()))
             }
-            Value::Integer(n) => Ok(LuaMessage::Integer(n as i64)),
-            Value::Number(n) => Ok(LuaMessage::Number(n as f64)),
+            Value::Integer(n) => Ok(LuaMessage::Integer(n)),
+            Value::Number(n) => Ok(LuaMessage::Number(n)),
             Value::Boolean(b) => Ok(LuaMessage::Boolean(b)),
             Value::Nil => Ok(LuaMessage::Nil),
-            Value::Table(t) => Ok(LuaMessage::Table(HashMap::from_lua(Value::Table(t), ctx)?)),
-            Value::Error(err) => {
-                panic!("Lua error: {:?}", err);
+            Value::LightUserData(ud) if ud.0.is_null() => Ok(LuaMessage::Nil),
+            Value::Table(t) => table_from_lua(t, ctx, &ctx.create_table()?),
+            Value::UserData(ud) => match ud.borrow::<LuaRecipient>() {
+                Ok(r) => Ok(LuaMessage::Recipient(r.0.clone())),
+                Err(_) => Err(LuaError::FromLuaConversionError {
+                    from: "userdata",
+                    to: "LuaMessage",
+                    message: None,
+                }),
+            },
+            Value::Error(err) => Err(err),
+            v => Err(LuaError::FromLuaConversionError {
+                from: type_name(&v),
+                to: "LuaMessage",
+                message: None,
+            }),
+        }
+    }
+}
+
+// `seen` contains the tables being converted, to detect cycles
+fn table_from_lua<'lua>(
+    t: Table<'lua>,
+    ctx: Context<'lua>,
+    seen: &Table<'lua>,
+) -> LuaResult<LuaMessage> {
+    if seen.raw_get::<_, bool>(t.clone())? {
+        return Err(LuaError::FromLuaConversionError {
+            from: "table",
+            to: "LuaMessage",
+            message: Some("table contains a cycle".to_string()),
+        });
+    }
+    seen.raw_set(t.clone(), true)?;
+
+    let pairs = t
+        .clone()
+        .pairs::<Value, Value>()
+        .collect::<LuaResult<Vec<(Value, Value)>>>()?;
+    let value = |v: Value<'lua>| match v {
+        Value::Table(t) => table_from_lua(t, ctx, seen),
+        v => LuaMessage::from_lua(v, ctx),
+    };
+
+    let msg = if is_sequence(&pairs) {
+        let mut items = vec![LuaMessage::Nil; pairs.len()];
+        for (k, v) in pairs {
+            if let Value::Integer(i) = k {
+                items[(i - 1) as usize] = value(v)?;
             }
-            _ => unimplemented!(),
         }
+        LuaMessage::Array(items)
+    } else {
+        let mut table = HashMap::with_capacity(pairs.len());
+        for (k, v) in pairs {
+            table.insert(String::from_lua(k, ctx)?, value(v)?);
+        }
+        LuaMessage::Table(table)
+    };
+
+    // the same table may appear again outside of itself
+    seen.raw_set(t, Value::Nil)?;
+    Ok(msg)
+}
+
+// keys are unique, a table with `n` keys in `1..n` is a sequence
+pub(crate) fn is_sequence(pairs: &[(Value, Value)]) -> bool {
+    let n = pairs.len() as i64;
+    n > 0
+        && pairs.iter().all(|(k, _)| match k {
+            Value::Integer(i) => *i >= 1 && *i <= n,
+            _ => false,
+        })
+}
+
+pub(crate) fn type_name(v: &Value) -> &'static str {
+    match v {
+        Value::Nil => "nil",
+        Value::Boolean(_) => "boolean",
+        Value::LightUserData(_) => "lightuserdata",
+        Value::Integer(_) => "integer",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Table(_) => "table",
+        Value::Function(_) => "function",
+        Value::Thread(_) => "thread",
+        Value::UserData(_) => "userdata",
+        Value::Error(_) => "error",
     }
 }
 
@@ -135,9 +394,14 @@ impl<'lua> ToLua<'lua> for LuaMessage {
             LuaMessage::Boolean(x) => Ok(Value::Boolean(x)),
             LuaMessage::Nil => Ok(Value::Nil),
             LuaMessage::Table(x) => Ok(Value::Table(ctx.create_table_from(x)?)),
-
-            // TODO: passing rust error to lua error?
-            _ => unimplemented!(),
+            LuaMessage::Array(x) => Ok(Value::Table(ctx.create_sequence_from(x)?)),
+            LuaMessage::Bytes(x) => Ok(Value::String(ctx.create_string(&x)?)),
+            LuaMessage::Recipient(r) => Ok(Value::UserData(ctx.create_userdata(LuaRecipient(r))?)),
+            LuaMessage::ThreadYield(_) => Err(LuaError::ToLuaConversionError {
+                from: "ThreadYield",
+                to: "Value",
+                message: Some("ThreadYield can't be passed to lua".to_string()),
+            }),
         }
     }
 }
@@ -241,15 +505,137 @@ mod tests {
         })
     }
 
-    #[should_panic]
+    #[test]
+    fn array() {
+        assert_eq!(
+            LuaMessage::from(vec![1, 2]),
+            LuaMessage::Array(vec![LuaMessage::Integer(1), LuaMessage::Integer(2)])
+        );
+
+        let lua = Lua::new();
+        lua.context(|ctx| {
+            let eval = |script: &str| {
+                let v: Value = ctx.load(script).eval().unwrap();
+                LuaMessage::from_lua(v, ctx).unwrap()
+            };
+
+            assert_eq!(eval("return {1, 2, 3}"), LuaMessage::from(vec![1, 2, 3]));
+            assert_eq!(
+                eval("return {{x = 1}, {x = 2}}"),
+                LuaMessage::from(vec![
+                    LuaMessage::from(
+                        vec![("x".to_string(), LuaMessage::from(1))]
+                            .into_iter()
+                            .collect::<HashMap<_, _>>()
+                    ),
+                    LuaMessage::from(
+                        vec![("x".to_string(), LuaMessage::from(2))]
+                            .into_iter()
+                            .collect::<HashMap<_, _>>()
+                    ),
+                ])
+            );
+            assert_eq!(eval("return {}"), LuaMessage::Table(HashMap::new()));
+
+            // mixed and sparse tables are tables with string keys
+            let mut t = HashMap::new();
+            t.insert("1".to_string(), LuaMessage::from("a"));
+            t.insert("x".to_string(), LuaMessage::from("b"));
+            assert_eq!(eval(r#"return {"a", x = "b"}"#), LuaMessage::Table(t));
+            let mut t = HashMap::new();
+            t.insert("1".to_string(), LuaMessage::from(1));
+            t.insert("3".to_string(), LuaMessage::from(3));
+            assert_eq!(eval("return {[1] = 1, [3] = 3}"), LuaMessage::Table(t));
+
+            // round trip
+            let v = LuaMessage::from(vec!["a", "b"]).to_lua(ctx).unwrap();
+            assert_eq!(
+                LuaMessage::from_lua(v, ctx).unwrap(),
+                LuaMessage::from(vec!["a", "b"])
+            );
+        })
+    }
+
+    #[test]
+    fn bytes() {
+        let lua = Lua::new();
+        lua.context(|ctx| {
+            let v: Value = ctx.load(r#"return "\xff\x00abc""#).eval().unwrap();
+            assert_eq!(
+                LuaMessage::from_lua(v.clone(), ctx).unwrap(),
+                LuaMessage::from(&b"\xff\x00abc"[..])
+            );
+
+            Utf8Policy::Error.install(ctx).unwrap();
+            assert!(LuaMessage::from_lua(v, ctx).is_err());
+
+            // valid UTF-8 is always a string
+            let v: Value = ctx.load(r#"return "abc""#).eval().unwrap();
+            assert_eq!(
+                LuaMessage::from_lua(v, ctx).unwrap(),
+                LuaMessage::from("abc")
+            );
+
+            // round trip
+            let v = LuaMessage::Bytes(vec![0xff, 0xfe]).to_lua(ctx).unwrap();
+            if let Value::String(s) = v {
+                assert_eq!(s.as_bytes(), &[0xff, 0xfe]);
+            } else {
+                panic!("should be a string");
+            }
+        })
+    }
+
     #[test]
     fn from_lua_error() {
-        use rlua::Error;
+        let lua = Lua::new();
+        lua.context(|ctx| {
+            let res =
+                LuaMessage::from_lua(Value::Error(LuaError::RuntimeError("foo".to_string())), ctx);
+            assert!(res.is_err());
+        })
+    }
 
+    #[test]
+    fn from_lua_function() {
         let lua = Lua::new();
         lua.context(|ctx| {
-            &LuaMessage::from_lua(Value::Error(Error::RuntimeError("foo".to_string())), ctx)
+            let f = ctx.create_function(|_, ()| Ok(())).unwrap();
+            let res = LuaMessage::from_lua(Value::Function(f), ctx);
+            if let Err(LuaError::FromLuaConversionError { from, .. }) = res {
+                assert_eq!(from, "function");
+            } else {
+                panic!("should return conversion error");
+            }
+        })
+    }
+
+    #[test]
+    fn from_lua_cycle() {
+        let lua = Lua::new();
+        lua.context(|ctx| {
+            let v: Value = ctx.load(r#"local t = {} t.t = t return t"#).eval().unwrap();
+            let res = LuaMessage::from_lua(v, ctx);
+            match res.map_err(LuaActorError::from) {
+                Err(LuaActorError::Conversion(msg)) => assert!(msg.contains("cycle")),
+                _ => panic!("should return conversion error"),
+            }
+
+            // a table referenced twice is not a cycle
+            let v: Value = ctx
+                .load(r#"local t = { 1 } return { a = t, b = { t } }"#)
+                .eval()
                 .unwrap();
+            let mut expected = HashMap::new();
+            expected.insert("a".to_string(), LuaMessage::from(vec![1]));
+            expected.insert(
+                "b".to_string(),
+                LuaMessage::Array(vec![LuaMessage::from(vec![1])]),
+            );
+            assert_eq!(
+                LuaMessage::from_lua(v, ctx).unwrap(),
+                LuaMessage::Table(expected)
+            );
         })
     }
 }
@@ -0,0 +1,430 @@
+//! MessagePack encoding of `LuaMessage`, enabled with the `msgpack` feature.
+//!
+//! `Integer` is encoded as a MessagePack integer and `Number` always as a 64-bit float,
+//! so they are distinguished after decoding. `Bytes` is encoded as binary, and `ThreadYield`
+//! as the extension type `THREAD_YIELD_EXT`. `Recipient` can't be encoded.
+use bytes::BytesMut;
+use rmpv::decode::{self, read_value};
+use rmpv::encode::write_value;
+use rmpv::Value;
+use tokio::codec::{Decoder, Encoder};
+
+use std::collections::HashMap;
+
+use crate::error::LuaActorError;
+use crate::message::LuaMessage;
+
+// extension type of `LuaMessage::ThreadYield`
+const THREAD_YIELD_EXT: i8 = 1;
+
+impl LuaMessage {
+    /// Encode the message with MessagePack.
+    pub fn to_msgpack(&self) -> Result<Vec<u8>, LuaActorError> {
+        let mut buf = vec![];
+        write_value(&mut buf, &to_value(self)?)
+            .map_err(|e| LuaActorError::Conversion(format!("msgpack: {}", e)))?;
+        Ok(buf)
+    }
+
+    /// Decode a message encoded with `to_msgpack`.
+    pub fn from_msgpack(bytes: &[u8]) -> Result<LuaMessage, LuaActorError> {
+        let mut rd = bytes;
+        let value = read_value(&mut rd).map_err(decode_error)?;
+        if !rd.is_empty() {
+            return Err(LuaActorError::Conversion(format!(
+                "msgpack: {} trailing bytes",
+                rd.len()
+            )));
+        }
+        from_value(value)
+    }
+}
+
+fn decode_error(err: decode::Error) -> LuaActorError {
+    LuaActorError::Conversion(format!("msgpack: {}", err))
+}
+
+fn to_value(msg: &LuaMessage) -> Result<Value, LuaActorError> {
+    Ok(match msg {
+        LuaMessage::String(s) => Value::from(s.as_str()),
+        LuaMessage::Integer(n) => Value::from(*n),
+        LuaMessage::Number(n) => Value::F64(*n),
+        LuaMessage::Boolean(b) => Value::Boolean(*b),
+        LuaMessage::Nil => Value::Nil,
+        LuaMessage::Table(t) => Value::Map(
+            t.iter()
+                .map(|(k, v)| Ok((Value::from(k.as_str()), to_value(v)?)))
+                .collect::<Result<_, LuaActorError>>()?,
+        ),
+        LuaMessage::Array(a) => Value::Array(a.iter().map(to_value).collect::<Result<_, _>>()?),
+        LuaMessage::Bytes(b) => Value::Binary(b.clone()),
+        LuaMessage::ThreadYield(tid) => Value::Ext(THREAD_YIELD_EXT, tid.as_bytes().to_vec()),
+        LuaMessage::Recipient(_) => {
+            return Err(LuaActorError::Conversion(
+                "msgpack: Recipient can't be encoded".to_string(),
+            ));
+        }
+    })
+}
+
+fn from_value(value: Value) -> Result<LuaMessage, LuaActorError> {
+    match value {
+        Value::Nil => Ok(LuaMessage::Nil),
+        Value::Boolean(b) => Ok(LuaMessage::Boolean(b)),
+        Value::Integer(n) => match n.as_i64() {
+            Some(n) => Ok(LuaMessage::Integer(n)),
+            // u64 which doesn't fit in a Lua integer
+            None => Ok(LuaMessage::Number(n.as_f64().unwrap_or(f64::NAN))),
+        },
+        Value::F32(n) => Ok(LuaMessage::Number(f64::from(n))),
+        Value::F64(n) => Ok(LuaMessage::Number(n)),
+        Value::String(s) => {
+            if s.is_str() {
+                Ok(LuaMessage::String(s.into_str().unwrap()))
+            } else {
+                Ok(LuaMessage::Bytes(s.into_bytes()))
+            }
+        }
+        Value::Binary(b) => Ok(LuaMessage::Bytes(b)),
+        Value::Array(a) => Ok(LuaMessage::Array(
+            a.into_iter().map(from_value).collect::<Result<_, _>>()?,
+        )),
+        Value::Map(m) => {
+            let mut t = HashMap::with_capacity(m.len());
+            for (k, v) in m {
+                let k = match k {
+                    Value::String(ref s) if s.is_str() => s.as_str().unwrap().to_string(),
+                    Value::Integer(n) => n.to_string(),
+                    k => {
+                        return Err(LuaActorError::Conversion(format!(
+                            "msgpack: unsupported table key {}",
+                            k
+                        )));
+                    }
+                };
+                t.insert(k, from_value(v)?);
+            }
+            Ok(LuaMessage::Table(t))
+        }
+        Value::Ext(THREAD_YIELD_EXT, tid) => String::from_utf8(tid)
+            .map(LuaMessage::ThreadYield)
+            .map_err(|e| LuaActorError::Conversion(format!("msgpack: {}", e))),
+        Value::Ext(ty, _) => Err(LuaActorError::Conversion(format!(
+            "msgpack: unsupported extension type {}",
+            ty
+        ))),
+    }
+}
+
+/// A `tokio` codec for a stream of MessagePack encoded `LuaMessage`s.
+///
+/// ```
+/// # extern crate actix_lua;
+/// # extern crate tokio;
+/// use actix_lua::{LuaMessage, LuaMessageCodec};
+/// use tokio::codec::FramedRead;
+///
+/// # let stream = std::io::Cursor::new(LuaMessage::from(42).to_msgpack().unwrap());
+/// // each item of `messages` is a `Result<LuaMessage, LuaActorError>`
+/// let messages = FramedRead::new(stream, LuaMessageCodec::new());
+/// ```
+///
+/// A message arriving in several reads is scanned once, the codec keeps track of the
+/// part of the message received so far.
+#[derive(Debug, Clone, Default)]
+pub struct LuaMessageCodec {
+    // length of the values of the current message received so far
+    scanned: usize,
+    // number of values left in each array or map the current message is in
+    remaining: Vec<u64>,
+}
+
+impl LuaMessageCodec {
+    pub fn new() -> LuaMessageCodec {
+        LuaMessageCodec::default()
+    }
+
+    // the length of the message at the start of `src` once it's complete
+    fn scan(&mut self, src: &[u8]) -> Result<Option<usize>, LuaActorError> {
+        while let Some((len, values)) = value_len(&src[self.scanned..])? {
+            self.scanned += len;
+            if values > 0 {
+                self.remaining.push(values);
+                continue;
+            }
+            // the value completes the containers it ends
+            loop {
+                match self.remaining.last_mut() {
+                    None => {
+                        let len = self.scanned;
+                        self.scanned = 0;
+                        return Ok(Some(len));
+                    }
+                    Some(n) => {
+                        *n -= 1;
+                        if *n > 0 {
+                            break;
+                        }
+                    }
+                }
+                self.remaining.pop();
+            }
+        }
+        Ok(None)
+    }
+}
+
+// the length of the value at the start of `src` without the values it contains,
+// and the number of contained values for arrays and maps.
+// `None` if the value isn't complete yet
+fn value_len(src: &[u8]) -> Result<Option<(usize, u64)>, LuaActorError> {
+    let marker = match src.first() {
+        Some(marker) => *marker,
+        None => return Ok(None),
+    };
+    // size of the length field after the marker
+    let size = |n: usize| -> Option<u64> {
+        let bytes = src.get(1..=n)?;
+        Some(bytes.iter().fold(0, |len, b| len << 8 | u64::from(*b)))
+    };
+    let (header, body, values) = match marker {
+        0x00..=0x7f | 0xc0 | 0xc2 | 0xc3 | 0xe0..=0xff => (1, 0, 0),
+        0x80..=0x8f => (1, 0, 2 * u64::from(marker & 0x0f)),
+        0x90..=0x9f => (1, 0, u64::from(marker & 0x0f)),
+        0xa0..=0xbf => (1, u64::from(marker & 0x1f), 0),
+        0xc4 | 0xd9 => match size(1) {
+            Some(len) => (2, len, 0),
+            None => return Ok(None),
+        },
+        0xc5 | 0xda => match size(2) {
+            Some(len) => (3, len, 0),
+            None => return Ok(None),
+        },
+        0xc6 | 0xdb => match size(4) {
+            Some(len) => (5, len, 0),
+            None => return Ok(None),
+        },
+        // ext: the length is followed by the type
+        0xc7 => match size(1) {
+            Some(len) => (3, len, 0),
+            None => return Ok(None),
+        },
+        0xc8 => match size(2) {
+            Some(len) => (4, len, 0),
+            None => return Ok(None),
+        },
+        0xc9 => match size(4) {
+            Some(len) => (6, len, 0),
+            None => return Ok(None),
+        },
+        0xcc | 0xd0 => (2, 0, 0),
+        0xcd | 0xd1 => (3, 0, 0),
+        0xca | 0xce | 0xd2 => (5, 0, 0),
+        0xcb | 0xcf | 0xd3 => (9, 0, 0),
+        // fixext
+        0xd4 => (3, 0, 0),
+        0xd5 => (4, 0, 0),
+        0xd6 => (6, 0, 0),
+        0xd7 => (10, 0, 0),
+        0xd8 => (18, 0, 0),
+        0xdc => match size(2) {
+            Some(n) => (3, 0, n),
+            None => return Ok(None),
+        },
+        0xdd => match size(4) {
+            Some(n) => (5, 0, n),
+            None => return Ok(None),
+        },
+        0xde => match size(2) {
+            Some(n) => (3, 0, 2 * n),
+            None => return Ok(None),
+        },
+        0xdf => match size(4) {
+            Some(n) => (5, 0, 2 * n),
+            None => return Ok(None),
+        },
+        _ => {
+            return Err(LuaActorError::Conversion(format!(
+                "msgpack: invalid marker {:#x}",
+                marker
+            )))
+        }
+    };
+    let len = header + body as usize;
+    if src.len() < len {
+        return Ok(None);
+    }
+    Ok(Some((len, values)))
+}
+
+impl Encoder for LuaMessageCodec {
+    type Item = LuaMessage;
+    type Error = LuaActorError;
+
+    fn encode(&mut self, msg: LuaMessage, dst: &mut BytesMut) -> Result<(), LuaActorError> {
+        dst.extend_from_slice(&msg.to_msgpack()?);
+        Ok(())
+    }
+}
+
+impl Decoder for LuaMessageCodec {
+    type Item = LuaMessage;
+    type Error = LuaActorError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<LuaMessage>, LuaActorError> {
+        // wait for the rest of the message
+        let len = match self.scan(src)? {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+        let frame = src.split_to(len);
+        let value = read_value(&mut &frame[..]).map_err(decode_error)?;
+        from_value(value).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::LuaActorBuilder;
+    use ::actix::prelude::*;
+    use futures::{Future, Stream};
+    use tokio::codec::FramedRead;
+
+    fn message() -> LuaMessage {
+        let mut inner = HashMap::new();
+        inner.insert("f".to_string(), LuaMessage::from(1.0));
+        inner.insert("i".to_string(), LuaMessage::from(1));
+        inner.insert("big".to_string(), LuaMessage::from(i64::MIN));
+        let mut t = HashMap::new();
+        t.insert("s".to_string(), LuaMessage::from("foo"));
+        t.insert("b".to_string(), LuaMessage::from(false));
+        t.insert("nil".to_string(), LuaMessage::Nil);
+        t.insert("inner".to_string(), LuaMessage::from(inner));
+        t.insert(
+            "a".to_string(),
+            LuaMessage::from(vec![LuaMessage::from(1), LuaMessage::from(2.5)]),
+        );
+        t.insert("bytes".to_string(), LuaMessage::from(&b"\xff\x00"[..]));
+        t.insert(
+            "yield".to_string(),
+            LuaMessage::ThreadYield("tid".to_string()),
+        );
+        LuaMessage::from(t)
+    }
+
+    #[test]
+    fn round_trip() {
+        let msg = message();
+        assert_eq!(
+            LuaMessage::from_msgpack(&msg.to_msgpack().unwrap()).unwrap(),
+            msg
+        );
+
+        // integer and float are kept apart
+        assert_eq!(
+            LuaMessage::from_msgpack(&LuaMessage::from(1.0).to_msgpack().unwrap()).unwrap(),
+            LuaMessage::Number(1.0)
+        );
+        assert_eq!(
+            LuaMessage::from_msgpack(&LuaMessage::from(1).to_msgpack().unwrap()).unwrap(),
+            LuaMessage::Integer(1)
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(LuaMessage::from_msgpack(&[]).is_err());
+        // unknown extension type
+        assert!(LuaMessage::from_msgpack(&[0xd4, 0x05, 0x00]).is_err());
+        let mut bytes = LuaMessage::from(1).to_msgpack().unwrap();
+        bytes.push(0);
+        assert!(LuaMessage::from_msgpack(&bytes).is_err());
+    }
+
+    #[test]
+    fn codec() {
+        let mut codec = LuaMessageCodec::new();
+        let mut buf = BytesMut::new();
+        codec.encode(message(), &mut buf).unwrap();
+        codec.encode(LuaMessage::from("bar"), &mut buf).unwrap();
+
+        // feed the bytes one by one
+        let bytes = buf.take();
+        let mut src = BytesMut::new();
+        let mut decoded = vec![];
+        for b in bytes.iter() {
+            src.extend_from_slice(&[*b]);
+            if let Some(msg) = codec.decode(&mut src).unwrap() {
+                decoded.push(msg);
+            }
+        }
+        assert!(src.is_empty());
+        assert_eq!(decoded, vec![message(), LuaMessage::from("bar")]);
+    }
+
+    #[test]
+    fn codec_partial() {
+        let long = "x".repeat(70000);
+        let messages = vec![
+            LuaMessage::from(long.as_str()),
+            LuaMessage::from(&long[..300]),
+            LuaMessage::Bytes(vec![1; 300]),
+            LuaMessage::ThreadYield("1".to_string()),
+            LuaMessage::from(vec![LuaMessage::from(vec![LuaMessage::Nil; 20]); 20]),
+            LuaMessage::from(HashMap::<String, LuaMessage>::new()),
+            LuaMessage::from(vec![LuaMessage::from(-1), LuaMessage::from(1i64 << 40)]),
+        ];
+        let mut codec = LuaMessageCodec::new();
+        for msg in messages {
+            let bytes = msg.to_msgpack().unwrap();
+            let mut src = BytesMut::new();
+            // the message is complete only with its last byte
+            for b in &bytes[..bytes.len() - 1] {
+                src.extend_from_slice(&[*b]);
+                assert_eq!(codec.decode(&mut src).unwrap(), None);
+            }
+            src.extend_from_slice(&bytes[bytes.len() - 1..]);
+            assert_eq!(codec.decode(&mut src).unwrap(), Some(msg));
+            assert!(src.is_empty());
+        }
+        let mut src = BytesMut::from(&[0xc1][..]);
+        assert!(codec.decode(&mut src).is_err());
+    }
+
+    #[test]
+    fn framed_actor() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(r#"return ctx.msg * 2"#)
+            .build()
+            .unwrap()
+            .start();
+
+        let mut bytes = vec![];
+        for i in 1..4 {
+            bytes.extend(LuaMessage::from(i).to_msgpack().unwrap());
+        }
+        let frames = FramedRead::new(std::io::Cursor::new(bytes), LuaMessageCodec::new());
+        Arbiter::spawn(
+            frames
+                .and_then(move |msg| addr.send(msg).map_err(LuaActorError::Mailbox))
+                .collect()
+                .map(|res| {
+                    assert_eq!(
+                        res,
+                        vec![
+                            LuaMessage::from(2),
+                            LuaMessage::from(4),
+                            LuaMessage::from(6)
+                        ]
+                    );
+                    System::current().stop();
+                })
+                .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+}
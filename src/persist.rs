@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use crate::message::LuaMessage;
+
+// file header, followed by the format version
+const MAGIC: &[u8] = b"ACTIXLUA";
+const VERSION: u8 = 1;
+
+const TAG_NIL: u8 = 0;
+const TAG_BOOLEAN: u8 = 1;
+const TAG_INTEGER: u8 = 2;
+const TAG_NUMBER: u8 = 3;
+const TAG_STRING: u8 = 4;
+const TAG_TABLE: u8 = 5;
+const TAG_ARRAY: u8 = 6;
+const TAG_BYTES: u8 = 7;
+
+/// When a `LuaActor` writes `ctx.state` to its state file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PersistPolicy {
+    /// Write the state when the actor is stopped.
+    OnStop,
+    /// Write the state after every `n` handled messages, and when the actor is stopped.
+    EveryMessages(u32),
+}
+
+/// Writes the state of a `LuaActor` to a file.
+pub(crate) struct Persistence {
+    path: PathBuf,
+    policy: PersistPolicy,
+    handled: u32,
+}
+
+impl Persistence {
+    pub fn new(path: PathBuf, policy: PersistPolicy) -> Persistence {
+        Persistence {
+            path,
+            policy,
+            handled: 0,
+        }
+    }
+
+    /// Read the state written previously. Returns `None` if there's no state file.
+    pub fn load(&self) -> io::Result<Option<LuaMessage>> {
+        match fs::read(&self.path) {
+            Ok(bytes) => decode(&bytes).map(Some),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save(&self, state: &LuaMessage) -> io::Result<()> {
+        // write to a temporary file first so a crash won't leave a truncated state file
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        fs::write(&tmp, encode(state)?)?;
+        fs::rename(&tmp, &self.path)
+    }
+
+    /// Count a handled message. Returns true if the state should be written.
+    pub fn handled(&mut self) -> bool {
+        match self.policy {
+            PersistPolicy::OnStop => false,
+            PersistPolicy::EveryMessages(n) => {
+                self.handled += 1;
+                if self.handled >= n {
+                    self.handled = 0;
+                    true
+                } else {
+                    false
+                }
+            }
+        }
+    }
+}
+
+/// Encode a `LuaMessage` with the state file format.
+pub(crate) fn encode(msg: &LuaMessage) -> io::Result<Vec<u8>> {
+    let mut buf = MAGIC.to_vec();
+    buf.push(VERSION);
+    encode_value(msg, &mut buf)?;
+    Ok(buf)
+}
+
+fn encode_value(msg: &LuaMessage, buf: &mut Vec<u8>) -> io::Result<()> {
+    match msg {
+        LuaMessage::Nil => buf.push(TAG_NIL),
+        LuaMessage::Boolean(b) => {
+            buf.push(TAG_BOOLEAN);
+            buf.push(*b as u8);
+        }
+        LuaMessage::Integer(n) => {
+            buf.push(TAG_INTEGER);
+            buf.extend_from_slice(&n.to_le_bytes());
+        }
+        LuaMessage::Number(n) => {
+            buf.push(TAG_NUMBER);
+            buf.extend_from_slice(&n.to_bits().to_le_bytes());
+        }
+        LuaMessage::String(s) => {
+            buf.push(TAG_STRING);
+            encode_bytes(s.as_bytes(), buf);
+        }
+        LuaMessage::Table(t) => {
+            buf.push(TAG_TABLE);
+            buf.extend_from_slice(&(t.len() as u32).to_le_bytes());
+            for (k, v) in t.iter() {
+                encode_bytes(k.as_bytes(), buf);
+                encode_value(v, buf)?;
+            }
+        }
+        LuaMessage::Array(a) => {
+            buf.push(TAG_ARRAY);
+            buf.extend_from_slice(&(a.len() as u32).to_le_bytes());
+            for v in a.iter() {
+                encode_value(v, buf)?;
+            }
+        }
+        LuaMessage::Bytes(b) => {
+            buf.push(TAG_BYTES);
+            encode_bytes(b, buf);
+        }
+        LuaMessage::ThreadYield(_) => return Err(invalid_data("ThreadYield can't be persisted")),
+        // addresses don't outlive the process, they are restored as `nil`
+        LuaMessage::Recipient(_) => buf.push(TAG_NIL),
+    }
+    Ok(())
+}
+
+fn encode_bytes(bytes: &[u8], buf: &mut Vec<u8>) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+/// Decode a `LuaMessage` encoded with `encode`.
+pub(crate) fn decode(bytes: &[u8]) -> io::Result<LuaMessage> {
+    if !bytes.starts_with(MAGIC) {
+        return Err(invalid_data("not a state file"));
+    }
+    let mut reader = Reader {
+        bytes,
+        pos: MAGIC.len(),
+    };
+    let version = reader.take(1)?[0];
+    if version != VERSION {
+        return Err(invalid_data(&format!(
+            "unsupported state file version {}",
+            version
+        )));
+    }
+    reader.value()
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.bytes.len() - self.pos < n {
+            return Err(invalid_data("unexpected end of state file"));
+        }
+        let slice = &self.bytes[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(slice)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let mut b = [0; 4];
+        b.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(b))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(b))
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|e| invalid_data(&e.to_string()))
+    }
+
+    fn value(&mut self) -> io::Result<LuaMessage> {
+        match self.take(1)?[0] {
+            TAG_NIL => Ok(LuaMessage::Nil),
+            TAG_BOOLEAN => Ok(LuaMessage::Boolean(self.take(1)?[0] != 0)),
+            TAG_INTEGER => Ok(LuaMessage::Integer(self.u64()? as i64)),
+            TAG_NUMBER => Ok(LuaMessage::Number(f64::from_bits(self.u64()?))),
+            TAG_STRING => Ok(LuaMessage::String(self.string()?)),
+            TAG_TABLE => {
+                let len = self.u32()?;
+                let mut t = HashMap::new();
+                for _ in 0..len {
+                    let k = self.string()?;
+                    t.insert(k, self.value()?);
+                }
+                Ok(LuaMessage::Table(t))
+            }
+            TAG_ARRAY => {
+                let len = self.u32()?;
+                let mut a = vec![];
+                for _ in 0..len {
+                    a.push(self.value()?);
+                }
+                Ok(LuaMessage::Array(a))
+            }
+            TAG_BYTES => {
+                let len = self.u32()? as usize;
+                Ok(LuaMessage::Bytes(self.take(len)?.to_vec()))
+            }
+            tag => Err(invalid_data(&format!("unknown tag {}", tag))),
+        }
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut inner = HashMap::new();
+        inner.insert("n".to_string(), LuaMessage::from(42.5));
+        inner.insert("b".to_string(), LuaMessage::from(true));
+        let mut t = HashMap::new();
+        t.insert("x".to_string(), LuaMessage::from(-1));
+        t.insert("s".to_string(), LuaMessage::from("foo"));
+        t.insert("t".to_string(), LuaMessage::from(inner));
+        t.insert("nil".to_string(), LuaMessage::Nil);
+        t.insert("a".to_string(), LuaMessage::from(vec![1, 2, 3]));
+        t.insert("bytes".to_string(), LuaMessage::from(&b"\xff\x00"[..]));
+        let msg = LuaMessage::from(t);
+
+        assert_eq!(decode(&encode(&msg).unwrap()).unwrap(), msg);
+    }
+
+    #[test]
+    fn recipient_as_nil() {
+        use crate::builder::LuaActorBuilder;
+        use ::actix::prelude::*;
+
+        let system = System::new("test");
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(r#"return nil"#)
+            .build()
+            .unwrap()
+            .start();
+        let msg = LuaMessage::from(vec![
+            LuaMessage::from(1),
+            LuaMessage::from(addr.recipient()),
+        ]);
+        assert_eq!(
+            decode(&encode(&msg).unwrap()).unwrap(),
+            LuaMessage::from(vec![LuaMessage::from(1), LuaMessage::Nil])
+        );
+        drop(system);
+    }
+
+    #[test]
+    fn unsupported_version() {
+        let mut bytes = encode(&LuaMessage::Nil).unwrap();
+        bytes[MAGIC.len()] = VERSION + 1;
+        assert!(decode(&bytes).is_err());
+        assert!(decode(b"foo").is_err());
+    }
+
+    #[test]
+    fn every_messages() {
+        let mut p = Persistence::new(PathBuf::from("state"), PersistPolicy::EveryMessages(2));
+        assert!(!p.handled());
+        assert!(p.handled());
+        assert!(!p.handled());
+        assert!(p.handled());
+    }
+}
@@ -0,0 +1,210 @@
+use ::actix::prelude::*;
+
+use crate::message::LuaMessage;
+use std::collections::HashMap;
+
+/// A system wide registry of named recipients.
+///
+/// `ctx.send` and `ctx.do_send` look up names which are not in the recipients of a `LuaActor`
+/// in the registry. They match names exactly, `*` is only a wildcard in `Lookup`. Actors are registered with `LuaActorBuilder::register_as`,
+/// `ctx.register_global(name)` in Lua, or the `Register` message.
+///
+/// `LuaActor`s are unregistered when they stop.
+///
+/// ```
+/// # extern crate actix;
+/// # extern crate actix_lua;
+/// use actix::prelude::*;
+/// use actix_lua::{Lookup, LuaRegistry};
+///
+/// # fn lookup() {
+/// // all recipients whose name starts with "worker."
+/// let workers = LuaRegistry::from_registry().send(Lookup("worker.*".to_string()));
+/// # }
+/// ```
+#[derive(Default)]
+pub struct LuaRegistry {
+    recipients: HashMap<String, Recipient<LuaMessage>>,
+}
+
+impl Actor for LuaRegistry {
+    type Context = Context<Self>;
+}
+
+impl Supervised for LuaRegistry {}
+
+impl SystemService for LuaRegistry {}
+
+/// Register `recipient` as `name` in `LuaRegistry`.
+///
+/// Returns the recipient previously registered with the same name.
+pub struct Register {
+    pub name: String,
+    pub recipient: Recipient<LuaMessage>,
+}
+
+impl Message for Register {
+    type Result = Option<Recipient<LuaMessage>>;
+}
+
+impl Handler<Register> for LuaRegistry {
+    type Result = Option<Recipient<LuaMessage>>;
+
+    fn handle(&mut self, msg: Register, _: &mut Context<Self>) -> Self::Result {
+        self.recipients.insert(msg.name, msg.recipient)
+    }
+}
+
+/// Remove the recipient registered as the given name from `LuaRegistry`.
+///
+/// Returns the removed recipient.
+pub struct Unregister(pub String);
+
+impl Message for Unregister {
+    type Result = Option<Recipient<LuaMessage>>;
+}
+
+impl Handler<Unregister> for LuaRegistry {
+    type Result = Option<Recipient<LuaMessage>>;
+
+    fn handle(&mut self, msg: Unregister, _: &mut Context<Self>) -> Self::Result {
+        self.recipients.remove(&msg.0)
+    }
+}
+
+/// Find the recipients whose name matches the given pattern in `LuaRegistry`, sorted by name.
+///
+/// `*` in the pattern matches any sequence of characters.
+pub struct Lookup(pub String);
+
+impl Message for Lookup {
+    type Result = Vec<(String, Recipient<LuaMessage>)>;
+}
+
+impl Handler<Lookup> for LuaRegistry {
+    type Result = MessageResult<Lookup>;
+
+    fn handle(&mut self, msg: Lookup, _: &mut Context<Self>) -> Self::Result {
+        let mut found = self
+            .recipients
+            .iter()
+            .filter(|(name, _)| matches(&msg.0, name))
+            .map(|(name, r)| (name.clone(), r.clone()))
+            .collect::<Vec<_>>();
+        found.sort_by(|a, b| a.0.cmp(&b.0));
+        MessageResult(found)
+    }
+}
+
+// remove all the names of a stopped actor
+pub(crate) struct UnregisterAll(pub Recipient<LuaMessage>);
+
+impl Message for UnregisterAll {
+    type Result = ();
+}
+
+impl Handler<UnregisterAll> for LuaRegistry {
+    type Result = ();
+
+    fn handle(&mut self, msg: UnregisterAll, _: &mut Context<Self>) -> Self::Result {
+        self.recipients.retain(|_, r| *r != msg.0);
+    }
+}
+
+// the recipient registered as exactly the given name, for names not in the recipients of an actor
+pub(crate) struct Get(pub String);
+
+impl Message for Get {
+    type Result = Option<Recipient<LuaMessage>>;
+}
+
+impl Handler<Get> for LuaRegistry {
+    type Result = Option<Recipient<LuaMessage>>;
+
+    fn handle(&mut self, msg: Get, _: &mut Context<Self>) -> Self::Result {
+        self.recipients.get(&msg.0).cloned()
+    }
+}
+
+// `*` matches any sequence of characters
+fn matches(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    if !name.starts_with(first) {
+        return false;
+    }
+    let mut rest = &name[first.len()..];
+    let parts = parts.collect::<Vec<_>>();
+    let (last, middle) = match parts.split_last() {
+        Some(split) => split,
+        // no wildcard
+        None => return rest.is_empty(),
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::LuaActorBuilder;
+    use futures::Future;
+
+    #[test]
+    fn wildcard() {
+        assert!(matches("worker", "worker"));
+        assert!(!matches("worker", "worker.1"));
+        assert!(matches("worker.*", "worker.1"));
+        assert!(matches("*", ""));
+        assert!(matches("*.log", "app.log"));
+        assert!(matches("a*b*c", "abc"));
+        assert!(matches("a*b*c", "a.b.b.c"));
+        assert!(!matches("a*b*c", "a.c"));
+        assert!(!matches("ab*ba", "aba"));
+    }
+
+    #[test]
+    fn register_and_lookup() {
+        let system = System::new("test");
+
+        let worker = LuaActorBuilder::new()
+            .on_handle_with_lua(r#"return ctx.msg"#)
+            .build()
+            .unwrap()
+            .start()
+            .recipient();
+
+        let registry = LuaRegistry::from_registry();
+        for name in &["worker.2", "worker.1", "other"] {
+            registry.do_send(Register {
+                name: name.to_string(),
+                recipient: worker.clone(),
+            });
+        }
+        let l = registry.send(Lookup("worker.*".to_string()));
+        Arbiter::spawn(
+            l.and_then(move |found| {
+                let names = found.into_iter().map(|(name, _)| name).collect::<Vec<_>>();
+                assert_eq!(names, vec!["worker.1", "worker.2"]);
+                registry
+                    .send(Unregister("other".to_string()))
+                    .and_then(move |removed| {
+                        assert!(removed.is_some());
+                        registry.send(Lookup("*".to_string()))
+                    })
+            })
+            .map(|found| {
+                assert_eq!(found.len(), 2);
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+}
@@ -0,0 +1,282 @@
+use rlua::{Context, Lua, StdLib, Table, Value};
+
+use crate::error::LuaActorError;
+
+// the base library and coroutines are required by the prelude
+const REQUIRED: StdLib = StdLib::from_bits_truncate(StdLib::BASE.bits() | StdLib::COROUTINE.bits());
+
+const MODULES: [(&str, StdLib); 8] = [
+    ("coroutine", StdLib::COROUTINE),
+    ("table", StdLib::TABLE),
+    ("io", StdLib::IO),
+    ("os", StdLib::OS),
+    ("string", StdLib::STRING),
+    ("utf8", StdLib::UTF8),
+    ("math", StdLib::MATH),
+    ("package", StdLib::PACKAGE),
+];
+
+/// `SandboxProfile` controls which parts of the Lua standard library are available to scripts.
+///
+/// Start from a preset and allow individual functions with `allow`:
+///
+/// ```
+/// use actix_lua::{LuaActorBuilder, SandboxProfile};
+///
+/// let actor = LuaActorBuilder::new()
+///     .sandbox(SandboxProfile::pure_compute().allow("os.time"))
+///     .on_handle_with_lua(r#"return os.time()"#)
+///     .build()
+///     .unwrap();
+/// ```
+///
+/// The base library and `coroutine` are always loaded since the context API depends on them.
+#[derive(Debug, Clone)]
+pub struct SandboxProfile {
+    libs: StdLib,
+    denied: Vec<String>,
+    allowed: Vec<String>,
+}
+
+impl Default for SandboxProfile {
+    fn default() -> SandboxProfile {
+        SandboxProfile::full()
+    }
+}
+
+impl SandboxProfile {
+    /// All standard libraries except `debug`. Same as `rlua::Lua::new()`.
+    pub fn full() -> SandboxProfile {
+        SandboxProfile {
+            libs: StdLib::ALL_NO_DEBUG,
+            denied: vec![],
+            allowed: vec![],
+        }
+    }
+
+    /// No access to files, processes, and environment variables.
+    ///
+    /// `io` and `package` are not loaded. `os` only provides `clock`, `date`, `difftime` and `time`.
+    /// `dofile`, `loadfile`, `load` and `string.dump` are removed, since `load` accepts binary chunks.
+    pub fn no_io() -> SandboxProfile {
+        SandboxProfile {
+            libs: StdLib::BASE
+                | StdLib::COROUTINE
+                | StdLib::TABLE
+                | StdLib::OS
+                | StdLib::STRING
+                | StdLib::UTF8
+                | StdLib::MATH,
+            denied: to_strings(&[
+                "dofile",
+                "load",
+                "loadfile",
+                "os.execute",
+                "os.exit",
+                "os.getenv",
+                "os.remove",
+                "os.rename",
+                "os.setlocale",
+                "os.tmpname",
+                "string.dump",
+            ]),
+            allowed: vec![],
+        }
+    }
+
+    /// Only computation on values: `string`, `table`, `math` and `utf8`.
+    ///
+    /// Scripts can't load code, print, or control the garbage collector.
+    pub fn pure_compute() -> SandboxProfile {
+        SandboxProfile {
+            libs: StdLib::BASE
+                | StdLib::COROUTINE
+                | StdLib::TABLE
+                | StdLib::STRING
+                | StdLib::UTF8
+                | StdLib::MATH,
+            denied: to_strings(&[
+                "collectgarbage",
+                "dofile",
+                "load",
+                "loadfile",
+                "print",
+                "string.dump",
+            ]),
+            allowed: vec![],
+        }
+    }
+
+    /// Allow a function removed by the profile, such as `"print"` or `"os.time"`.
+    ///
+    /// The library of the function is loaded if the profile doesn't load it.
+    /// Only the allowed functions of the library are available in this case.
+    pub fn allow(mut self, name: &str) -> SandboxProfile {
+        self.allowed.push(name.to_string());
+        self
+    }
+
+    // true if nothing is removed from `full()`
+    pub(crate) fn is_full(&self) -> bool {
+        self.libs.contains(StdLib::ALL_NO_DEBUG) && self.denied.is_empty()
+    }
+
+    fn is_allowed(&self, name: &str) -> bool {
+        self.allowed.iter().any(|n| n == name)
+    }
+
+    // libraries needed by the allowed functions
+    fn allowed_libs(&self) -> StdLib {
+        self.allowed
+            .iter()
+            .fold(StdLib::empty(), |libs, name| libs | lib_of(name))
+    }
+
+    /// Create a new VM with the profile applied.
+    pub(crate) fn create_vm(&self) -> Result<Lua, LuaActorError> {
+        let vm = Lua::new_with(self.libs | self.allowed_libs() | REQUIRED);
+        vm.context(|ctx| self.apply(ctx))?;
+        Ok(vm)
+    }
+
+    /// Remove everything the profile doesn't allow from the given VM.
+    pub(crate) fn apply(&self, ctx: Context) -> Result<(), LuaActorError> {
+        let globals = ctx.globals();
+        let loaded: Option<Table> = match globals.get::<_, Value>("package")? {
+            Value::Table(package) => package.get("loaded").ok(),
+            _ => None,
+        };
+
+        for (name, lib) in MODULES.iter() {
+            if self.libs.contains(*lib) || REQUIRED.contains(*lib) {
+                continue;
+            }
+            let module = match globals.get::<_, Value>(*name)? {
+                Value::Table(module) => module,
+                _ => continue,
+            };
+
+            // `require` depends on the `package` table
+            if *lib == StdLib::PACKAGE && self.is_allowed("require") {
+                continue;
+            }
+
+            // keep only the allowed functions of the module
+            let prefix = format!("{}.", name);
+            let allowed: Vec<&String> = self
+                .allowed
+                .iter()
+                .filter(|n| n.starts_with(&prefix))
+                .collect();
+            let replacement = if allowed.is_empty() {
+                Value::Nil
+            } else {
+                let partial = ctx.create_table()?;
+                for name in allowed {
+                    let key = &name[prefix.len()..];
+                    partial.set(key, module.get::<_, Value>(key)?)?;
+                }
+                Value::Table(partial)
+            };
+            globals.set(*name, replacement.clone())?;
+            if let Some(ref loaded) = loaded {
+                loaded.set(*name, replacement)?;
+            }
+        }
+        if !self.libs.contains(StdLib::PACKAGE) && !self.is_allowed("require") {
+            globals.set("require", Value::Nil)?;
+        }
+
+        for name in self.denied.iter() {
+            if self.is_allowed(name) {
+                continue;
+            }
+            match name.find('.') {
+                Some(i) => {
+                    if let Value::Table(module) = globals.get::<_, Value>(&name[..i])? {
+                        module.set(&name[i + 1..], Value::Nil)?;
+                    }
+                }
+                None => globals.set(name.as_str(), Value::Nil)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn lib_of(name: &str) -> StdLib {
+    if name == "require" {
+        return StdLib::PACKAGE;
+    }
+    let module = match name.find('.') {
+        Some(i) => &name[..i],
+        None => return StdLib::BASE,
+    };
+    MODULES
+        .iter()
+        .find(|(n, _)| *n == module)
+        .map_or(StdLib::empty(), |(_, lib)| *lib)
+}
+
+fn to_strings(names: &[&str]) -> Vec<String> {
+    names.iter().map(|n| n.to_string()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_nil(vm: &Lua, expr: &str) -> bool {
+        vm.context(|ctx| {
+            let v: Value = ctx.load(expr).eval().unwrap();
+            matches!(v, Value::Nil)
+        })
+    }
+
+    #[test]
+    fn no_io() {
+        let vm = SandboxProfile::no_io().create_vm().unwrap();
+        assert!(is_nil(&vm, "io"));
+        assert!(is_nil(&vm, "require"));
+        assert!(is_nil(&vm, "dofile"));
+        assert!(is_nil(&vm, "os.execute"));
+        assert!(!is_nil(&vm, "os.time"));
+        assert!(is_nil(&vm, "load"));
+        assert!(is_nil(&vm, "string.dump"));
+        assert!(!is_nil(&vm, "string.format"));
+        assert!(is_nil(&vm, "debug"));
+    }
+
+    #[test]
+    fn pure_compute() {
+        let vm = SandboxProfile::pure_compute().create_vm().unwrap();
+        assert!(is_nil(&vm, "os"));
+        assert!(is_nil(&vm, "load"));
+        assert!(is_nil(&vm, "print"));
+        assert!(!is_nil(&vm, "string.format"));
+        assert!(!is_nil(&vm, "coroutine.create"));
+    }
+
+    #[test]
+    fn allow() {
+        let vm = SandboxProfile::pure_compute()
+            .allow("print")
+            .allow("os.time")
+            .create_vm()
+            .unwrap();
+        assert!(!is_nil(&vm, "print"));
+        assert!(!is_nil(&vm, "os.time"));
+        assert!(is_nil(&vm, "os.execute"));
+    }
+
+    #[test]
+    fn apply_to_vm() {
+        let vm = Lua::new();
+        vm.context(|ctx| SandboxProfile::no_io().apply(ctx))
+            .unwrap();
+        assert!(is_nil(&vm, "io"));
+        assert!(is_nil(&vm, "package"));
+        assert!(is_nil(&vm, "os.exit"));
+    }
+}
@@ -0,0 +1,773 @@
+//! `serde` support for `LuaMessage`, enabled with the `serde` feature.
+//!
+//! Structs are converted to `Table`, sequences to `Array`, and enums the same way as `serde_json`:
+//! unit variants become a `String`, other variants a `Table` with the variant name as the only key.
+use serde::de::value::{MapDeserializer, SeqDeserializer};
+use serde::de::{
+    self, DeserializeOwned, DeserializeSeed, EnumAccess, IntoDeserializer, MapAccess, SeqAccess,
+    VariantAccess, Visitor,
+};
+use serde::ser::{self, Serialize};
+use serde::{forward_to_deserialize_any, Deserialize, Deserializer};
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::error::LuaActorError;
+use crate::message::LuaMessage;
+
+impl LuaMessage {
+    /// Convert any `Serialize` value to a `LuaMessage`.
+    pub fn from_serialize<T: Serialize + ?Sized>(value: &T) -> Result<LuaMessage, LuaActorError> {
+        value.serialize(Serializer)
+    }
+
+    /// Convert the message to any `Deserialize` value.
+    pub fn deserialize_into<T: DeserializeOwned>(self) -> Result<T, LuaActorError> {
+        T::deserialize(self)
+    }
+}
+
+impl ser::Error for LuaActorError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        LuaActorError::Conversion(msg.to_string())
+    }
+}
+
+impl de::Error for LuaActorError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        LuaActorError::Conversion(msg.to_string())
+    }
+}
+
+impl Serialize for LuaMessage {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            LuaMessage::String(s) => serializer.serialize_str(s),
+            LuaMessage::Integer(n) => serializer.serialize_i64(*n),
+            LuaMessage::Number(n) => serializer.serialize_f64(*n),
+            LuaMessage::Boolean(b) => serializer.serialize_bool(*b),
+            LuaMessage::Nil => serializer.serialize_unit(),
+            LuaMessage::Table(t) => serializer.collect_map(t),
+            LuaMessage::Array(a) => serializer.collect_seq(a),
+            LuaMessage::Bytes(b) => serializer.serialize_bytes(b),
+            LuaMessage::ThreadYield(_) => {
+                Err(ser::Error::custom("ThreadYield can't be serialized"))
+            }
+            LuaMessage::Recipient(_) => Err(ser::Error::custom("Recipient can't be serialized")),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for LuaMessage {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<LuaMessage, D::Error> {
+        deserializer.deserialize_any(MessageVisitor)
+    }
+}
+
+struct MessageVisitor;
+
+impl<'de> Visitor<'de> for MessageVisitor {
+    type Value = LuaMessage;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a value convertible to LuaMessage")
+    }
+
+    fn visit_bool<E>(self, v: bool) -> Result<LuaMessage, E> {
+        Ok(LuaMessage::Boolean(v))
+    }
+
+    fn visit_i64<E>(self, v: i64) -> Result<LuaMessage, E> {
+        Ok(LuaMessage::Integer(v))
+    }
+
+    fn visit_u64<E>(self, v: u64) -> Result<LuaMessage, E> {
+        Ok(integer(v))
+    }
+
+    fn visit_f64<E>(self, v: f64) -> Result<LuaMessage, E> {
+        Ok(LuaMessage::Number(v))
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<LuaMessage, E> {
+        Ok(LuaMessage::String(v.to_string()))
+    }
+
+    fn visit_string<E>(self, v: String) -> Result<LuaMessage, E> {
+        Ok(LuaMessage::String(v))
+    }
+
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<LuaMessage, E> {
+        Ok(LuaMessage::Bytes(v.to_vec()))
+    }
+
+    fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<LuaMessage, E> {
+        Ok(LuaMessage::Bytes(v))
+    }
+
+    fn visit_none<E>(self) -> Result<LuaMessage, E> {
+        Ok(LuaMessage::Nil)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, d: D) -> Result<LuaMessage, D::Error> {
+        d.deserialize_any(self)
+    }
+
+    fn visit_unit<E>(self) -> Result<LuaMessage, E> {
+        Ok(LuaMessage::Nil)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<LuaMessage, A::Error> {
+        let mut a = vec![];
+        while let Some(v) = seq.next_element()? {
+            a.push(v);
+        }
+        Ok(LuaMessage::Array(a))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<LuaMessage, A::Error> {
+        let mut t = HashMap::new();
+        while let Some((k, v)) = map.next_entry::<LuaMessage, LuaMessage>()? {
+            t.insert(key(k).map_err(de::Error::custom)?, v);
+        }
+        Ok(LuaMessage::Table(t))
+    }
+}
+
+// u64 which doesn't fit in a Lua integer becomes a number
+fn integer(v: u64) -> LuaMessage {
+    if v <= i64::MAX as u64 {
+        LuaMessage::Integer(v as i64)
+    } else {
+        LuaMessage::Number(v as f64)
+    }
+}
+
+// keys of `LuaMessage::Table` are strings
+fn key(k: LuaMessage) -> Result<String, LuaActorError> {
+    match k {
+        LuaMessage::String(s) => Ok(s),
+        LuaMessage::Integer(n) => Ok(n.to_string()),
+        LuaMessage::Boolean(b) => Ok(b.to_string()),
+        k => Err(LuaActorError::Conversion(format!(
+            "table key must be a string or an integer, got {:?}",
+            k
+        ))),
+    }
+}
+
+/// Serializes a value into a `LuaMessage`.
+struct Serializer;
+
+impl ser::Serializer for Serializer {
+    type Ok = LuaMessage;
+    type Error = LuaActorError;
+
+    type SerializeSeq = SerializeArray;
+    type SerializeTuple = SerializeArray;
+    type SerializeTupleStruct = SerializeArray;
+    type SerializeTupleVariant = SerializeArray;
+    type SerializeMap = SerializeTable;
+    type SerializeStruct = SerializeTable;
+    type SerializeStructVariant = SerializeTable;
+
+    fn serialize_bool(self, v: bool) -> Result<LuaMessage, LuaActorError> {
+        Ok(LuaMessage::Boolean(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<LuaMessage, LuaActorError> {
+        Ok(LuaMessage::Integer(i64::from(v)))
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<LuaMessage, LuaActorError> {
+        Ok(LuaMessage::Integer(i64::from(v)))
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<LuaMessage, LuaActorError> {
+        Ok(LuaMessage::Integer(i64::from(v)))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<LuaMessage, LuaActorError> {
+        Ok(LuaMessage::Integer(v))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<LuaMessage, LuaActorError> {
+        Ok(LuaMessage::Integer(i64::from(v)))
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<LuaMessage, LuaActorError> {
+        Ok(LuaMessage::Integer(i64::from(v)))
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<LuaMessage, LuaActorError> {
+        Ok(LuaMessage::Integer(i64::from(v)))
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<LuaMessage, LuaActorError> {
+        Ok(integer(v))
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<LuaMessage, LuaActorError> {
+        Ok(LuaMessage::Number(f64::from(v)))
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<LuaMessage, LuaActorError> {
+        Ok(LuaMessage::Number(v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<LuaMessage, LuaActorError> {
+        Ok(LuaMessage::String(v.to_string()))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<LuaMessage, LuaActorError> {
+        Ok(LuaMessage::String(v.to_string()))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<LuaMessage, LuaActorError> {
+        Ok(LuaMessage::Bytes(v.to_vec()))
+    }
+
+    fn serialize_none(self) -> Result<LuaMessage, LuaActorError> {
+        Ok(LuaMessage::Nil)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, v: &T) -> Result<LuaMessage, LuaActorError> {
+        v.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<LuaMessage, LuaActorError> {
+        Ok(LuaMessage::Nil)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<LuaMessage, LuaActorError> {
+        Ok(LuaMessage::Nil)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<LuaMessage, LuaActorError> {
+        Ok(LuaMessage::String(variant.to_string()))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        v: &T,
+    ) -> Result<LuaMessage, LuaActorError> {
+        v.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        v: &T,
+    ) -> Result<LuaMessage, LuaActorError> {
+        let mut t = HashMap::new();
+        t.insert(variant.to_string(), v.serialize(self)?);
+        Ok(LuaMessage::Table(t))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<SerializeArray, LuaActorError> {
+        Ok(SerializeArray {
+            variant: None,
+            array: Vec::with_capacity(len.unwrap_or(0)),
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SerializeArray, LuaActorError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<SerializeArray, LuaActorError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeArray, LuaActorError> {
+        Ok(SerializeArray {
+            variant: Some(variant),
+            array: Vec::with_capacity(len),
+        })
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<SerializeTable, LuaActorError> {
+        Ok(SerializeTable {
+            variant: None,
+            table: HashMap::new(),
+            key: None,
+        })
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<SerializeTable, LuaActorError> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<SerializeTable, LuaActorError> {
+        Ok(SerializeTable {
+            variant: Some(variant),
+            table: HashMap::new(),
+            key: None,
+        })
+    }
+}
+
+// wrap the value of an enum variant in a table
+fn wrap(variant: Option<&'static str>, v: LuaMessage) -> LuaMessage {
+    match variant {
+        Some(name) => {
+            let mut t = HashMap::new();
+            t.insert(name.to_string(), v);
+            LuaMessage::Table(t)
+        }
+        None => v,
+    }
+}
+
+struct SerializeArray {
+    variant: Option<&'static str>,
+    array: Vec<LuaMessage>,
+}
+
+impl ser::SerializeSeq for SerializeArray {
+    type Ok = LuaMessage;
+    type Error = LuaActorError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, v: &T) -> Result<(), LuaActorError> {
+        self.array.push(v.serialize(Serializer)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<LuaMessage, LuaActorError> {
+        Ok(wrap(self.variant, LuaMessage::Array(self.array)))
+    }
+}
+
+impl ser::SerializeTuple for SerializeArray {
+    type Ok = LuaMessage;
+    type Error = LuaActorError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, v: &T) -> Result<(), LuaActorError> {
+        ser::SerializeSeq::serialize_element(self, v)
+    }
+
+    fn end(self) -> Result<LuaMessage, LuaActorError> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeArray {
+    type Ok = LuaMessage;
+    type Error = LuaActorError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, v: &T) -> Result<(), LuaActorError> {
+        ser::SerializeSeq::serialize_element(self, v)
+    }
+
+    fn end(self) -> Result<LuaMessage, LuaActorError> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeTupleVariant for SerializeArray {
+    type Ok = LuaMessage;
+    type Error = LuaActorError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, v: &T) -> Result<(), LuaActorError> {
+        ser::SerializeSeq::serialize_element(self, v)
+    }
+
+    fn end(self) -> Result<LuaMessage, LuaActorError> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+struct SerializeTable {
+    variant: Option<&'static str>,
+    table: HashMap<String, LuaMessage>,
+    key: Option<String>,
+}
+
+impl ser::SerializeMap for SerializeTable {
+    type Ok = LuaMessage;
+    type Error = LuaActorError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, k: &T) -> Result<(), LuaActorError> {
+        self.key = Some(key(k.serialize(Serializer)?)?);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, v: &T) -> Result<(), LuaActorError> {
+        let k = self
+            .key
+            .take()
+            .ok_or_else(|| LuaActorError::Conversion("value without a key".to_string()))?;
+        self.table.insert(k, v.serialize(Serializer)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<LuaMessage, LuaActorError> {
+        Ok(wrap(self.variant, LuaMessage::Table(self.table)))
+    }
+}
+
+impl ser::SerializeStruct for SerializeTable {
+    type Ok = LuaMessage;
+    type Error = LuaActorError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        k: &'static str,
+        v: &T,
+    ) -> Result<(), LuaActorError> {
+        self.table.insert(k.to_string(), v.serialize(Serializer)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<LuaMessage, LuaActorError> {
+        ser::SerializeMap::end(self)
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeTable {
+    type Ok = LuaMessage;
+    type Error = LuaActorError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        k: &'static str,
+        v: &T,
+    ) -> Result<(), LuaActorError> {
+        ser::SerializeStruct::serialize_field(self, k, v)
+    }
+
+    fn end(self) -> Result<LuaMessage, LuaActorError> {
+        ser::SerializeMap::end(self)
+    }
+}
+
+impl<'de> IntoDeserializer<'de, LuaActorError> for LuaMessage {
+    type Deserializer = LuaMessage;
+
+    fn into_deserializer(self) -> LuaMessage {
+        self
+    }
+}
+
+// integer fields accept floats with integral values like `FromLuaMessage` does,
+// lua arithmetic may produce them, e.g. `10 / 2`
+macro_rules! deserialize_integer {
+    ($($method:ident)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LuaActorError> {
+                match self {
+                    LuaMessage::Number(n)
+                        if n.fract() == 0.0 && n >= i64::MIN as f64 && n < i64::MAX as f64 =>
+                    {
+                        visitor.visit_i64(n as i64)
+                    }
+                    v => v.deserialize_any(visitor),
+                }
+            }
+        )*
+    };
+}
+
+impl<'de> Deserializer<'de> for LuaMessage {
+    type Error = LuaActorError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LuaActorError> {
+        match self {
+            LuaMessage::String(s) => visitor.visit_string(s),
+            LuaMessage::Integer(n) => visitor.visit_i64(n),
+            LuaMessage::Number(n) => visitor.visit_f64(n),
+            LuaMessage::Boolean(b) => visitor.visit_bool(b),
+            LuaMessage::Nil => visitor.visit_unit(),
+            LuaMessage::Table(t) => {
+                let mut map = MapDeserializer::new(t.into_iter());
+                let v = visitor.visit_map(&mut map)?;
+                map.end()?;
+                Ok(v)
+            }
+            LuaMessage::Array(a) => {
+                let mut seq = SeqDeserializer::new(a.into_iter());
+                let v = visitor.visit_seq(&mut seq)?;
+                seq.end()?;
+                Ok(v)
+            }
+            LuaMessage::Bytes(b) => visitor.visit_byte_buf(b),
+            LuaMessage::ThreadYield(_) => {
+                Err(de::Error::custom("ThreadYield can't be deserialized"))
+            }
+            LuaMessage::Recipient(_) => Err(de::Error::custom("Recipient can't be deserialized")),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LuaActorError> {
+        match self {
+            LuaMessage::Nil => visitor.visit_none(),
+            v => visitor.visit_some(v),
+        }
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, LuaActorError> {
+        match self {
+            // an empty lua table is converted to an empty `Table`
+            LuaMessage::Table(ref t) if t.is_empty() => {
+                visitor.visit_seq(SeqDeserializer::new(Vec::<LuaMessage>::new().into_iter()))
+            }
+            v => v.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, LuaActorError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, LuaActorError> {
+        match self {
+            LuaMessage::String(variant) => visitor.visit_enum(Enum {
+                variant,
+                value: None,
+            }),
+            LuaMessage::Table(t) if t.len() == 1 => {
+                let (variant, value) = t.into_iter().next().unwrap();
+                visitor.visit_enum(Enum {
+                    variant,
+                    value: Some(value),
+                })
+            }
+            v => Err(LuaActorError::Conversion(format!(
+                "expected a string or a table with a single key for an enum, got {:?}",
+                v
+            ))),
+        }
+    }
+
+    deserialize_integer! {
+        deserialize_i8 deserialize_i16 deserialize_i32 deserialize_i64
+        deserialize_u8 deserialize_u16 deserialize_u32 deserialize_u64
+    }
+
+    forward_to_deserialize_any! {
+        bool i128 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct tuple tuple_struct map struct
+        identifier ignored_any
+    }
+}
+
+struct Enum {
+    variant: String,
+    value: Option<LuaMessage>,
+}
+
+impl<'de> EnumAccess<'de> for Enum {
+    type Error = LuaActorError;
+    type Variant = Variant;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, Variant), LuaActorError> {
+        let variant = seed.deserialize(LuaMessage::String(self.variant))?;
+        Ok((variant, Variant(self.value)))
+    }
+}
+
+struct Variant(Option<LuaMessage>);
+
+impl Variant {
+    fn value(self) -> Result<LuaMessage, LuaActorError> {
+        self.0
+            .ok_or_else(|| LuaActorError::Conversion("expected a variant with a value".to_string()))
+    }
+}
+
+impl<'de> VariantAccess<'de> for Variant {
+    type Error = LuaActorError;
+
+    fn unit_variant(self) -> Result<(), LuaActorError> {
+        match self.0 {
+            None | Some(LuaMessage::Nil) => Ok(()),
+            Some(v) => Err(LuaActorError::Conversion(format!(
+                "expected a unit variant, got {:?}",
+                v
+            ))),
+        }
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(
+        self,
+        seed: T,
+    ) -> Result<T::Value, LuaActorError> {
+        seed.deserialize(self.value()?)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, LuaActorError> {
+        self.value()?.deserialize_seq(visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, LuaActorError> {
+        self.value()?.deserialize_any(visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::LuaActorBuilder;
+    use ::actix::prelude::*;
+    use futures::Future;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Shape {
+        Empty,
+        Circle(f64),
+        Rect { w: i64, h: i64 },
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Order {
+        id: u32,
+        name: String,
+        tags: Vec<String>,
+        note: Option<String>,
+        shapes: Vec<Shape>,
+    }
+
+    fn order() -> Order {
+        Order {
+            id: 1,
+            name: "foo".to_string(),
+            tags: vec!["a".to_string(), "b".to_string()],
+            note: None,
+            shapes: vec![Shape::Empty, Shape::Circle(1.5), Shape::Rect { w: 2, h: 3 }],
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let msg = LuaMessage::from_serialize(&order()).unwrap();
+        if let LuaMessage::Table(ref t) = msg {
+            assert_eq!(t["id"], LuaMessage::from(1));
+            assert_eq!(t["tags"], LuaMessage::from(vec!["a", "b"]));
+        } else {
+            panic!("should be a table");
+        }
+        assert_eq!(msg.deserialize_into::<Order>().unwrap(), order());
+    }
+
+    #[test]
+    fn deserialize_error() {
+        let res = LuaMessage::from("foo").deserialize_into::<Order>();
+        assert!(matches!(res, Err(LuaActorError::Conversion(_))));
+    }
+
+    #[test]
+    fn integral_number() {
+        let mut t = HashMap::new();
+        t.insert("w".to_string(), LuaMessage::Number(2.0));
+        t.insert("h".to_string(), LuaMessage::from(3));
+        let mut shape = HashMap::new();
+        shape.insert("Rect".to_string(), LuaMessage::from(t));
+        assert_eq!(
+            LuaMessage::from(shape).deserialize_into::<Shape>().unwrap(),
+            Shape::Rect { w: 2, h: 3 }
+        );
+        assert_eq!(LuaMessage::Number(5.0).deserialize_into::<u8>().unwrap(), 5);
+        assert!(LuaMessage::Number(5.5).deserialize_into::<u8>().is_err());
+        assert!(LuaMessage::Number(-1.0).deserialize_into::<u8>().is_err());
+        // floats are kept as floats when the type doesn't ask for an integer
+        assert_eq!(
+            LuaMessage::deserialize(LuaMessage::Number(5.0)).unwrap(),
+            LuaMessage::Number(5.0)
+        );
+    }
+
+    #[test]
+    fn serde_trait() {
+        let msg = LuaMessage::from(vec![LuaMessage::from(1), LuaMessage::from("a")]);
+        let v = LuaMessage::from_serialize(&msg).unwrap();
+        assert_eq!(v, msg);
+        assert_eq!(
+            LuaMessage::deserialize(v).unwrap(),
+            LuaMessage::from(vec![LuaMessage::from(1), LuaMessage::from("a")])
+        );
+    }
+
+    #[test]
+    fn through_actor() {
+        let system = System::new("test");
+
+        let addr = LuaActorBuilder::new()
+            .on_handle_with_lua(
+                r#"
+                local order = ctx.msg
+                order.id = order.id + 1
+                order.tags = {}
+                order.note = order.name .. "!"
+                return order
+                "#,
+            )
+            .build()
+            .unwrap()
+            .start();
+
+        let l = addr.send(LuaMessage::from_serialize(&order()).unwrap());
+        Arbiter::spawn(
+            l.map(|res| {
+                let res: Order = res.deserialize_into().unwrap();
+                assert_eq!(res.id, 2);
+                assert!(res.tags.is_empty());
+                assert_eq!(res.note, Some("foo!".to_string()));
+                assert_eq!(res.shapes, order().shapes);
+                System::current().stop();
+            })
+            .map_err(|e| println!("actor dead {}", e)),
+        );
+
+        system.run();
+    }
+}
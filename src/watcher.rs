@@ -0,0 +1,100 @@
+use std::fs;
+use std::io;
+use std::time::{Duration, SystemTime};
+
+use crate::actor::ReloadScripts;
+
+/// Polls the script files of a `LuaActor` for changes.
+pub(crate) struct ScriptWatcher {
+    pub interval: Duration,
+    files: Vec<WatchedFile>,
+}
+
+struct WatchedFile {
+    name: &'static str,
+    path: String,
+    modified: Option<SystemTime>,
+}
+
+impl ScriptWatcher {
+    pub fn new(interval: Duration) -> ScriptWatcher {
+        ScriptWatcher {
+            interval,
+            files: vec![],
+        }
+    }
+
+    /// Watch the file at `path` as the script `name`.
+    pub fn watch(&mut self, name: &'static str, path: &str) {
+        self.files.push(WatchedFile {
+            name,
+            path: path.to_string(),
+            modified: modified(path).ok(),
+        });
+    }
+
+    /// Returns the content of the scripts changed since the last poll.
+    pub fn poll(&mut self) -> io::Result<Option<ReloadScripts>> {
+        let mut scripts = ReloadScripts::default();
+        let mut changed = false;
+        for file in self.files.iter_mut() {
+            let m = modified(&file.path)?;
+            if file.modified == Some(m) {
+                continue;
+            }
+            // remember the change even if the script fails to compile,
+            // so it's not reloaded again until the next change.
+            file.modified = Some(m);
+
+            let script = Some(read_to_string(&file.path)?);
+            match file.name {
+                "started" => scripts.started = script,
+                "handle" => scripts.handle = script,
+                _ => scripts.stopped = script,
+            }
+            changed = true;
+        }
+
+        if changed {
+            Ok(Some(scripts))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+fn modified(path: &str) -> io::Result<SystemTime> {
+    fs::metadata(path)?.modified()
+}
+
+pub(crate) fn read_to_string(path: &str) -> io::Result<String> {
+    fs::read_to_string(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::thread;
+
+    #[test]
+    fn poll() {
+        let path = env::temp_dir().join(format!("actix-lua-watcher-{}.lua", std::process::id()));
+        let path = path.to_str().unwrap();
+        fs::write(path, "return 1").unwrap();
+
+        let mut watcher = ScriptWatcher::new(Duration::from_millis(10));
+        watcher.watch("handle", path);
+        assert!(watcher.poll().unwrap().is_none());
+
+        // make sure the modified time changes
+        thread::sleep(Duration::from_millis(10));
+        fs::write(path, "return 2").unwrap();
+        let scripts = watcher.poll().unwrap().unwrap();
+        assert_eq!(scripts.handle, Some("return 2".to_string()));
+        assert_eq!(scripts.started, None);
+        assert!(watcher.poll().unwrap().is_none());
+
+        fs::remove_file(path).unwrap();
+    }
+}